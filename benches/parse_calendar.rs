@@ -0,0 +1,17 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use shinbukan_ics::{parse_calendar, Config, MonthResult};
+
+fn bench_parse_calendar(c: &mut Criterion) {
+    let input = include_str!("../src/fixtures/2024-06.html");
+    let config = Config::default();
+    c.bench_function("parse_calendar 2024-06", |b| {
+        b.iter(|| {
+            let mut result = MonthResult::new(2024, 6);
+            parse_calendar(&mut result, std::hint::black_box(input), &config);
+            result
+        })
+    });
+}
+
+criterion_group!(benches, bench_parse_calendar);
+criterion_main!(benches);
@@ -0,0 +1,1045 @@
+//! Runtime configuration, gathered from the command line, the environment and an optional TOML file
+//!
+//! When a setting is given in several places, the command line wins over the environment, which wins over the
+//! configuration file, which wins over the built-in defaults.
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc, Weekday};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_CAL_DESCRIPTION: &str =
+    "Shinbukan dojo schedule, generated automatically from the online calendar and refreshed periodically";
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Events dated before this day are left out of the output
+    pub drop_before: Option<DateAnchor>,
+
+    /// ORGANIZER property set on each event
+    pub organizer: Option<Organizer>,
+
+    /// CONTACT property set on each event
+    pub contact: Option<String>,
+
+    /// Hours below this one are afternoon hours, written in 12-hour format without the am/pm indication
+    pub afternoon_before: usize,
+
+    /// Fetch exactly these months instead of the rolling window around today
+    pub months: Vec<YearMonth>,
+
+    /// Number of months before the current one that the rolling window fetches
+    pub months_back: u32,
+
+    /// Number of months after the current one that the rolling window fetches
+    pub months_ahead: u32,
+
+    /// Number of errors tolerated before the run is considered failed
+    pub fail_threshold: usize,
+
+    /// Venue names that can lead a cell's text, to be moved into the LOCATION of the event
+    pub venues: Vec<String>,
+
+    /// Add the source page and fetch time to the DESCRIPTION of each event
+    pub provenance: bool,
+
+    /// Add an HTML version of the DESCRIPTION in X-ALT-DESC, keeping the red notes and links of the page
+    pub html_description: bool,
+
+    /// Add the month of the page each event comes from in X-SHINBUKAN-SOURCE-MONTH, for tools that patch the pages
+    pub source_month: bool,
+
+    /// SUMMARY of the events that only have a time, instead of leaving it empty with a warning
+    pub empty_summary: Option<String>,
+
+    /// Maximum number of characters of a SUMMARY, longer ones being truncated with the full text in DESCRIPTION
+    pub max_summary_len: Option<usize>,
+
+    /// Characters decorating the time ranges, stripped from around them before they are parsed
+    pub decorations: String,
+
+    /// Characters separating the groups sharing a session, as in `19:00-21:00 少年部/一般部`, each of which then gets
+    /// its own event
+    pub split_summary_on: String,
+
+    /// Add an all-day event summarizing the errors of each month that had some
+    pub include_errors_in_calendar: bool,
+
+    /// Add an all-day event on the first of each month telling how many sessions it has
+    pub month_summary_event: bool,
+
+    /// Events that are not on the pages, like national holidays or closures of the dojo, added to those of their month
+    pub extra_events: Vec<ExtraEvent>,
+
+    /// URL of the calendar pages, where `{year}` and `{month}` get replaced with the zero-padded year and month
+    pub url_template: String,
+
+    /// URL of a mirror of the calendar pages, used for the months that could not be fetched from `url_template`
+    pub fallback_url_template: Option<String>,
+
+    /// Only speak HTTP/1.1 to the hosts of the calendar pages
+    pub http1_only: bool,
+
+    /// Open a new connection for each page, instead of reusing idle ones
+    pub no_keepalive: bool,
+
+    /// Close connections that stayed idle for this many seconds, instead of after reqwest's default of 90
+    pub pool_idle_timeout_secs: Option<u64>,
+
+    /// Number of pages fetched at the same time
+    pub concurrency: usize,
+
+    /// Addresses to connect to for these hosts instead of resolving them, e.g. to fetch from a local server, the port
+    /// being the one of the URL
+    pub resolve: BTreeMap<String, IpAddr>,
+
+    /// Size above which a page is not read any further, and fails to fetch
+    pub max_body_bytes: usize,
+
+    /// Texts of the pages served instead of the calendar when the session expired, which make the month fail with an
+    /// authentication error rather than parsing errors
+    pub auth_failure_markers: Vec<String>,
+
+    /// Stop at the first month whose page is an authentication failure, as the other ones will most likely be too
+    pub auth_fail_fast: bool,
+
+    /// State file of the incremental mode, reusing the events rendered for months whose page did not change
+    pub since_file: Option<PathBuf>,
+
+    /// Coordinates of the dojo, for the map pin of Apple clients
+    pub geo: Option<Geo>,
+
+    /// Add an X-APPLE-STRUCTURED-LOCATION to the events that have a LOCATION, when `geo` is set
+    pub apple_location: bool,
+
+    /// COLOR property set on each event
+    pub event_color: Option<Color>,
+
+    /// Colors of the events whose SUMMARY contains each keyword, which also becomes their CATEGORIES
+    pub category_colors: BTreeMap<String, Color>,
+
+    /// Only output the events, without the VCALENDAR around them
+    pub events_only: bool,
+
+    /// Line ending after the last line of the calendar
+    pub final_newline: FinalNewline,
+
+    /// Timezone in which the times of the calendar are written
+    pub source_tz: chrono_tz::Tz,
+
+    /// Timezone to give the times of the events in, along with its VTIMEZONE, instead of UTC
+    pub display_tz: Option<chrono_tz::Tz>,
+
+    /// Timezone whose date is "today", for the window of fetched months and the relative dates
+    pub window_tz: chrono_tz::Tz,
+
+    /// Write a JSON summary of the run to this file, for monitoring
+    pub status_file: Option<PathBuf>,
+
+    /// Minimum total number of events, below which the parser is considered broken
+    pub min_events: usize,
+
+    /// Minimum number of events, as a percentage of the previous run's recorded in `since_file`
+    pub min_events_vs_previous: Option<usize>,
+
+    /// Merge timed events with the same description that directly follow one another on the same day
+    pub merge_adjacent: bool,
+
+    /// Combine the all-day events of each day into a single one
+    pub merge_fullday: bool,
+
+    /// Description of the calendar as a whole, an empty one meaning none
+    pub cal_description: String,
+
+    /// Name of this deployment, telling its calendars, summaries and status files apart from those of others in PRODID
+    /// and in the reports, an empty one meaning none
+    pub instance_label: String,
+
+    /// Domain part of the UIDs, to keep them unique when several deployments feed the same calendar
+    pub uid_domain: String,
+
+    /// Leave the URL property out of the events
+    pub no_url_property: bool,
+
+    /// Only give the events their UID, DTSTAMP, DTSTART, DTEND and SUMMARY, leaving out all the other properties
+    pub compact: bool,
+
+    /// URL property of the events instead of the source page, where `{year}`, `{month}` and `{day}` get replaced
+    pub event_url: Option<String>,
+
+    /// Credentials for the calendar pages, sent with HTTP basic authentication to the primary source only
+    pub credentials: Option<Credentials>,
+
+    /// Hours at which to show all-day events, as timed events, instead of as all-day ones
+    pub fullday_as_timed: Option<TimeRange>,
+
+    /// Keywords, like `終日`, that make a line an all-day event even if it starts with a time range
+    pub all_day_keywords: Vec<String>,
+
+    /// Leave months without any event or error out of the summary table and the status file
+    pub prune_empty: bool,
+
+    /// Set the DTSTAMP of events to midnight UTC of the day of the run, instead of the time of the run
+    pub pin_dtstamp: bool,
+
+    /// Drop events more than this many days after today
+    pub horizon_days: Option<u32>,
+
+    /// Warn about timed events at the same place that overlap on the same day
+    pub check_overlaps: bool,
+
+    /// Minimum duration of timed events, below which they are most likely typos and handled as per `on_short_event`
+    pub min_interval_mins: Option<usize>,
+
+    /// What to do with the timed events shorter than `min_interval_mins`
+    pub on_short_event: ShortEvents,
+
+    /// Prepend the weekday to the SUMMARY of each event, written in this language
+    pub weekday_prefix: Option<Locale>,
+
+    /// Values of the `summary` attribute of the tables holding the schedule
+    pub schedule_summaries: Vec<String>,
+
+    /// Also write one calendar per month, named `YYYY-MM.ics`, into this directory
+    pub split_output: Option<PathBuf>,
+
+    /// Write the calendar to this file, atomically, instead of to stdout
+    pub output: Option<PathBuf>,
+
+    /// Also write the SHA-256 checksum of `output` next to it, with a `.sha256` suffix
+    pub write_checksum: bool,
+
+    /// Existing calendar to output with its events from `uid_domain` replaced by the generated ones, keeping the
+    /// others
+    pub merge_into: Option<PathBuf>,
+
+    /// Append the local times of timed events, e.g. `(JST 19:00-21:00)`, to their SUMMARY
+    pub timezone_output_display: bool,
+
+    /// Earliest year that can be fetched, to catch typos in `now` or `months`
+    pub min_year: usize,
+
+    /// Latest year that can be fetched, to catch typos in `now` or `months`
+    pub max_year: usize,
+
+    /// What to output: the events, as ICS or jCal, or only when the dojo is busy
+    pub format: OutputFormat,
+
+    /// Count all-day events as busy in the `freebusy` format
+    pub freebusy_full_day: bool,
+
+    /// Do not warn about pages that are not valid EUC-JP
+    pub tolerate_encoding_errors: bool,
+
+    /// Treat pages that are not valid EUC-JP as errors rather than warnings
+    pub strict: bool,
+
+    /// Print the parsed form of each month to stderr, to debug wrong events
+    pub dump_parsed: bool,
+
+    /// Print how each line of the pages was turned into events to stderr, to debug the heuristics
+    pub explain: bool,
+
+    /// What to do when several events get the same UID, which clients would show as a single one
+    pub on_uid_collision: UidCollision,
+
+    /// Order of the events in the calendar
+    pub sort_by: SortBy,
+
+    /// Make the events that repeat with the same time and contents on other days into a single one, with an RDATE per
+    /// other day
+    pub rdate_repeats: bool,
+
+    /// Language setting the defaults of the other settings that depend on one, like `all_day_keywords`
+    pub locale: Locale,
+
+    /// Days to parse the cells of, all of them if empty, to debug the parsing of a few days
+    #[serde(skip)]
+    pub only_days: Vec<usize>,
+
+    /// Reference time of this run, used to resolve relative dates and as the DTSTAMP of events
+    #[serde(skip)]
+    pub now: DateTime<Utc>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::for_locale(Locale::Ja)
+    }
+}
+
+impl Config {
+    /// The defaults for users of this language
+    pub fn for_locale(locale: Locale) -> Config {
+        Config {
+            drop_before: None,
+            organizer: None,
+            contact: None,
+            afternoon_before: 8,
+            months: Vec::new(),
+            months_back: 2,
+            months_ahead: 12,
+            fail_threshold: 0,
+            venues: Vec::new(),
+            provenance: false,
+            html_description: false,
+            source_month: false,
+            empty_summary: None,
+            max_summary_len: None,
+            decorations: String::from("・()（）【】"),
+            split_summary_on: String::new(),
+            include_errors_in_calendar: false,
+            month_summary_event: false,
+            extra_events: Vec::new(),
+            url_template: String::from("http://brionac.s17.xrea.com/schedule/homepage/homepage/calendar/{year}/{year}{month}.html"),
+            fallback_url_template: None,
+            http1_only: false,
+            no_keepalive: false,
+            pool_idle_timeout_secs: None,
+            concurrency: 16,
+            resolve: BTreeMap::new(),
+            max_body_bytes: 4 * 1024 * 1024,
+            auth_failure_markers: Vec::new(),
+            auth_fail_fast: false,
+            since_file: None,
+            geo: None,
+            apple_location: false,
+            event_color: None,
+            category_colors: BTreeMap::new(),
+            events_only: false,
+            final_newline: FinalNewline::Crlf,
+            source_tz: chrono_tz::Asia::Tokyo,
+            display_tz: None,
+            window_tz: chrono_tz::Asia::Tokyo,
+            status_file: None,
+            min_events: 0,
+            min_events_vs_previous: None,
+            merge_adjacent: false,
+            merge_fullday: false,
+            cal_description: String::from(DEFAULT_CAL_DESCRIPTION),
+            instance_label: String::new(),
+            uid_domain: String::from("shinbukan-ics"),
+            no_url_property: false,
+            compact: false,
+            event_url: None,
+            credentials: None,
+            fullday_as_timed: None,
+            all_day_keywords: locale.all_day_keywords().iter().map(|k| String::from(*k)).collect(),
+            prune_empty: false,
+            pin_dtstamp: false,
+            horizon_days: None,
+            check_overlaps: false,
+            min_interval_mins: None,
+            on_short_event: ShortEvents::Warn,
+            weekday_prefix: None,
+            dump_parsed: false,
+            explain: false,
+            split_output: None,
+            output: None,
+            write_checksum: false,
+            merge_into: None,
+            timezone_output_display: false,
+            min_year: 2000,
+            max_year: 2100,
+            format: OutputFormat::Ics,
+            freebusy_full_day: false,
+            tolerate_encoding_errors: false,
+            strict: false,
+            schedule_summaries: vec![String::from("日程"), String::from("行事")],
+            on_uid_collision: UidCollision::Suffix,
+            sort_by: SortBy::Date,
+            rdate_repeats: false,
+            locale,
+            only_days: Vec::new(),
+            now: Utc::now(),
+        }
+    }
+
+    /// Parse a TOML configuration, using the defaults of its `locale` for all unset keys
+    pub fn from_toml(toml: &str) -> anyhow::Result<Config> {
+        Config::from_toml_with_locale(toml, None)
+    }
+
+    /// Parse a TOML configuration, with `locale` replacing the one it sets, if any
+    pub fn from_toml_with_locale(toml: &str, locale: Option<Locale>) -> anyhow::Result<Config> {
+        // Parsing once first keeps the error messages pointing into the file
+        let config: Config = toml::from_str(toml)?;
+        let locale = locale.unwrap_or(config.locale);
+        if locale == Locale::Ja {
+            return Ok(Config { locale, ..config });
+        }
+        let mut merged = toml::Table::try_from(Config::for_locale(locale))?;
+        merged.extend(toml::from_str::<toml::Table>(toml)?);
+        merged.insert(String::from("locale"), toml::Value::try_from(locale)?);
+        Ok(merged.try_into()?)
+    }
+
+    /// The configuration as a TOML file, with the password redacted
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+
+    /// The configuration as JSON, with the password redacted
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Config> {
+        Config::from_file_with_locale(path, None)
+    }
+
+    /// Load a TOML configuration file, with `locale` replacing the one it sets, if any
+    pub fn from_file_with_locale(path: &Path, locale: Option<Locale>) -> anyhow::Result<Config> {
+        let toml = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading configuration file {path:?}"))?;
+        Config::from_toml_with_locale(&toml, locale).with_context(|| format!("Parsing configuration file {path:?}"))
+    }
+
+    /// The date of `now` in `window_tz`
+    pub fn today(&self) -> NaiveDate {
+        self.now.with_timezone(&self.window_tz).date_naive()
+    }
+}
+
+/// A reference day given in the configuration, resolved once the current date is known
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DateAnchor {
+    /// Today, shifted by the given number of days
+    Today(i64),
+    Date(NaiveDate),
+}
+
+impl DateAnchor {
+    pub fn resolve(self, today: NaiveDate) -> NaiveDate {
+        match self {
+            DateAnchor::Today(offset) => today + chrono::Duration::days(offset),
+            DateAnchor::Date(date) => date,
+        }
+    }
+}
+
+impl std::str::FromStr for DateAnchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<DateAnchor> {
+        if let Some(offset) = s.strip_prefix("today") {
+            if offset.is_empty() {
+                return Ok(DateAnchor::Today(0));
+            }
+            let days = offset
+                .strip_suffix('d')
+                .filter(|o| o.starts_with(['-', '+']))
+                .and_then(|o| o.parse().ok())
+                .ok_or_else(|| anyhow!("Expected `today`, `today-Nd` or `today+Nd`, got {s:?}"))?;
+            return Ok(DateAnchor::Today(days));
+        }
+        let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .map_err(|e| anyhow!("Expected `today[±Nd]` or a YYYY-MM-DD date, got {s:?}: {e}"))?;
+        Ok(DateAnchor::Date(date))
+    }
+}
+
+impl TryFrom<String> for DateAnchor {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<DateAnchor> {
+        s.parse()
+    }
+}
+
+impl From<DateAnchor> for String {
+    fn from(anchor: DateAnchor) -> String {
+        match anchor {
+            DateAnchor::Today(0) => String::from("today"),
+            DateAnchor::Today(offset) => format!("today{offset:+}d"),
+            DateAnchor::Date(date) => date.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// A calendar month, given as `YYYY-MM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct YearMonth {
+    pub year: usize,
+    pub month: usize,
+}
+
+impl std::str::FromStr for YearMonth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<YearMonth> {
+        let invalid = || anyhow!("Expected a YYYY-MM year-month, got {s:?}");
+        let (year, month) = s.split_once('-').ok_or_else(invalid)?;
+        if year.len() != 4 || month.len() != 2 {
+            return Err(invalid());
+        }
+        let year = year.parse().map_err(|_| invalid())?;
+        let month = month.parse().map_err(|_| invalid())?;
+        if !(1..=12).contains(&month) {
+            return Err(invalid());
+        }
+        Ok(YearMonth { year, month })
+    }
+}
+
+impl TryFrom<String> for YearMonth {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<YearMonth> {
+        s.parse()
+    }
+}
+
+impl From<YearMonth> for String {
+    fn from(ym: YearMonth) -> String {
+        format!("{:04}-{:02}", ym.year, ym.month)
+    }
+}
+
+/// The `ORGANIZER` of the generated events, given as `Name <mailto:address>` or `mailto:address`
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Organizer {
+    name: Option<String>,
+    address: String,
+}
+
+impl std::str::FromStr for Organizer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Organizer> {
+        let s = s.trim();
+        let (name, uri) = match s.strip_suffix('>').and_then(|s| s.split_once('<')) {
+            Some((name, uri)) => (Some(name.trim()).filter(|n| !n.is_empty()), uri.trim()),
+            None => (None, s),
+        };
+        let Some(address) = uri.strip_prefix("mailto:") else {
+            return Err(anyhow!("Organizer must be a mailto: URI, got {uri:?}"));
+        };
+        let valid = match address.split_once('@') {
+            Some((local, domain)) => !local.is_empty() && !domain.is_empty() && !domain.contains('@'),
+            None => false,
+        };
+        if !valid || address.contains(char::is_whitespace) {
+            return Err(anyhow!("Organizer {address:?} is not a valid email address"));
+        }
+        Ok(Organizer {
+            name: name.map(str::to_owned),
+            address: address.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for Organizer {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Organizer> {
+        s.parse()
+    }
+}
+
+impl From<Organizer> for String {
+    fn from(organizer: Organizer) -> String {
+        match organizer.name {
+            Some(name) => format!("{name} <mailto:{}>", organizer.address),
+            None => format!("mailto:{}", organizer.address),
+        }
+    }
+}
+
+impl std::fmt::Display for Organizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            // Parameter values containing separators must be quoted
+            Some(name) if name.contains([':', ';', ',']) => write!(f, "ORGANIZER;CN=\"{name}\":mailto:{}", self.address),
+            Some(name) => write!(f, "ORGANIZER;CN={name}:mailto:{}", self.address),
+            None => write!(f, "ORGANIZER:mailto:{}", self.address),
+        }
+    }
+}
+
+/// An event added to those of the pages, given as `YYYY-MM-DD [HH:MM-HH:MM] SUMMARY`, without a time for all-day ones
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ExtraEvent {
+    pub date: NaiveDate,
+    pub time: Option<TimeRange>,
+    pub summary: String,
+}
+
+impl ExtraEvent {
+    /// Read a file listing one event per line, skipping the blank lines and those starting with `#`
+    pub fn load(path: &Path) -> anyhow::Result<Vec<ExtraEvent>> {
+        let list = std::fs::read_to_string(path).with_context(|| format!("Reading extra events {path:?}"))?;
+        list.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(i, line)| line.parse().with_context(|| format!("Parsing line {} of extra events {path:?}", i + 1)))
+            .collect()
+    }
+}
+
+impl std::str::FromStr for ExtraEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<ExtraEvent> {
+        let invalid = || anyhow!("Expected an event as `YYYY-MM-DD [HH:MM-HH:MM] SUMMARY`, got {s:?}");
+        let (date, rest) = s.trim().split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| invalid())?;
+        let rest = rest.trim_start();
+        let (time, summary) = match rest.split_once(char::is_whitespace).map(|(time, summary)| (time.parse::<TimeRange>(), summary)) {
+            Some((Ok(time), summary)) => (Some(time), summary.trim()),
+            // Summaries may well start with something that is not a time range, like a year
+            Some((Err(_), _)) | None => (None, rest),
+        };
+        if summary.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ExtraEvent { date, time, summary: summary.to_owned() })
+    }
+}
+
+impl TryFrom<String> for ExtraEvent {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<ExtraEvent> {
+        s.parse()
+    }
+}
+
+impl From<ExtraEvent> for String {
+    fn from(event: ExtraEvent) -> String {
+        match event.time {
+            Some(time) => format!("{} {} {}", event.date, String::from(time), event.summary),
+            None => format!("{} {}", event.date, event.summary),
+        }
+    }
+}
+
+/// A range of hours within a day, given as `HH:MM-HH:MM`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeRange {
+    pub from: NaiveTime,
+    pub to: NaiveTime,
+}
+
+impl std::str::FromStr for TimeRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<TimeRange> {
+        let invalid = || anyhow!("Expected a time range as `HH:MM-HH:MM`, got {s:?}");
+        let (from, to) = s.split_once('-').ok_or_else(invalid)?;
+        let from = NaiveTime::parse_from_str(from.trim(), "%H:%M").map_err(|_| invalid())?;
+        let to = NaiveTime::parse_from_str(to.trim(), "%H:%M").map_err(|_| invalid())?;
+        if to <= from {
+            return Err(anyhow!("The time range {s:?} ends before it starts"));
+        }
+        Ok(TimeRange { from, to })
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<TimeRange> {
+        s.parse()
+    }
+}
+
+impl From<TimeRange> for String {
+    fn from(range: TimeRange) -> String {
+        format!("{}-{}", range.from.format("%H:%M"), range.to.format("%H:%M"))
+    }
+}
+
+/// Format of the generated calendar, given as `ics`, `freebusy` or `jcal`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// One VEVENT per event
+    Ics,
+    /// A single VFREEBUSY with the periods when events take place
+    Freebusy,
+    /// The same as `Ics`, as jCal JSON
+    Jcal,
+}
+
+/// Handling of events sharing a UID, given as `suffix`, `error` or `ignore`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UidCollision {
+    /// Keep the UID of the first event, and suffix those of the others with their rank
+    Suffix,
+    /// Fail the run, keeping the UIDs as they are
+    Error,
+    /// Only warn, keeping the UIDs as they are
+    Ignore,
+}
+
+/// Handling of timed events shorter than `min_interval_mins`, given as `warn` or `drop`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShortEvents {
+    /// Keep the events, with a warning
+    Warn,
+    /// Leave the events out of the calendar, with a warning
+    Drop,
+}
+
+/// Order of the events in the calendar, given as `date`, `category` or `summary`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    /// By start time
+    Date,
+    /// Grouped by CATEGORIES, in the order of their names and with the events without one last, then by start time
+    Category,
+    /// By SUMMARY, then by start time
+    Summary,
+}
+
+/// Line ending after the last line of the calendar, given as `crlf` or `none`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinalNewline {
+    /// A CRLF, as RFC 5545 ends every line with one
+    Crlf,
+    /// Nothing after `END:VCALENDAR`
+    #[serde(rename = "none")]
+    #[value(name = "none")]
+    Omit,
+}
+
+/// Language of the text added to the calendar, given as `ja` or `en`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    Ja,
+    En,
+}
+
+impl Locale {
+    /// Short name of the weekday, as shown in calendars
+    pub fn weekday(self, weekday: Weekday) -> &'static str {
+        let (ja, en) = match weekday {
+            Weekday::Mon => ("月", "Mon"),
+            Weekday::Tue => ("火", "Tue"),
+            Weekday::Wed => ("水", "Wed"),
+            Weekday::Thu => ("木", "Thu"),
+            Weekday::Fri => ("金", "Fri"),
+            Weekday::Sat => ("土", "Sat"),
+            Weekday::Sun => ("日", "Sun"),
+        };
+        match self {
+            Locale::Ja => ja,
+            Locale::En => en,
+        }
+    }
+
+    /// SUMMARY of the event giving the number of sessions of a month
+    pub fn month_summary(self, sessions: usize) -> String {
+        match (self, sessions) {
+            (Locale::Ja, _) => format!("今月の稽古: {sessions}回"),
+            (Locale::En, 1) => String::from("1 class this month"),
+            (Locale::En, _) => format!("{sessions} classes this month"),
+        }
+    }
+
+    /// Default keywords making a line an all-day event, the Japanese ones being kept as the pages are in Japanese
+    fn all_day_keywords(self) -> &'static [&'static str] {
+        match self {
+            Locale::Ja => &["終日", "休館"],
+            Locale::En => &["終日", "休館", "All day", "Closed"],
+        }
+    }
+}
+
+/// HTTP basic authentication credentials, given as `user:password`
+#[derive(Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl std::str::FromStr for Credentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Credentials> {
+        // User names cannot contain colons, passwords can
+        let (user, password) = s.split_once(':').ok_or_else(|| anyhow!("Expected credentials as `user:password`"))?;
+        Ok(Credentials {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for Credentials {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Credentials> {
+        s.parse()
+    }
+}
+
+// Keep the password out of logs and error messages
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("user", &self.user).field("password", &"[redacted]").finish()
+    }
+}
+
+// Configurations are only serialized to be shown, so the password is redacted there too
+impl serde::Serialize for Credentials {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{}:[redacted]", self.user))
+    }
+}
+
+/// Geographic coordinates, given as `latitude,longitude` in degrees
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Geo {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl std::str::FromStr for Geo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Geo> {
+        let invalid = || anyhow!("Expected `latitude,longitude` in degrees, got {s:?}");
+        let (lat, lon) = s.split_once(',').ok_or_else(invalid)?;
+        let lat: f64 = lat.trim().parse().map_err(|_| invalid())?;
+        let lon: f64 = lon.trim().parse().map_err(|_| invalid())?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(invalid());
+        }
+        Ok(Geo { lat, lon })
+    }
+}
+
+impl TryFrom<String> for Geo {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Geo> {
+        s.parse()
+    }
+}
+
+impl From<Geo> for String {
+    fn from(geo: Geo) -> String {
+        format!("{},{}", geo.lat, geo.lon)
+    }
+}
+
+/// The CSS3 color names, which is what the COLOR property accepts
+const CSS3_COLORS: &[&str] = &[
+    "aliceblue", "antiquewhite", "aqua", "aquamarine", "azure", "beige", "bisque", "black", "blanchedalmond", "blue",
+    "blueviolet", "brown", "burlywood", "cadetblue", "chartreuse", "chocolate", "coral", "cornflowerblue", "cornsilk",
+    "crimson", "cyan", "darkblue", "darkcyan", "darkgoldenrod", "darkgray", "darkgreen", "darkgrey", "darkkhaki",
+    "darkmagenta", "darkolivegreen", "darkorange", "darkorchid", "darkred", "darksalmon", "darkseagreen",
+    "darkslateblue", "darkslategray", "darkslategrey", "darkturquoise", "darkviolet", "deeppink", "deepskyblue",
+    "dimgray", "dimgrey", "dodgerblue", "firebrick", "floralwhite", "forestgreen", "fuchsia", "gainsboro",
+    "ghostwhite", "gold", "goldenrod", "gray", "green", "greenyellow", "grey", "honeydew", "hotpink", "indianred",
+    "indigo", "ivory", "khaki", "lavender", "lavenderblush", "lawngreen", "lemonchiffon", "lightblue", "lightcoral",
+    "lightcyan", "lightgoldenrodyellow", "lightgray", "lightgreen", "lightgrey", "lightpink", "lightsalmon",
+    "lightseagreen", "lightskyblue", "lightslategray", "lightslategrey", "lightsteelblue", "lightyellow", "lime",
+    "limegreen", "linen", "magenta", "maroon", "mediumaquamarine", "mediumblue", "mediumorchid", "mediumpurple",
+    "mediumseagreen", "mediumslateblue", "mediumspringgreen", "mediumturquoise", "mediumvioletred", "midnightblue",
+    "mintcream", "mistyrose", "moccasin", "navajowhite", "navy", "oldlace", "olive", "olivedrab", "orange",
+    "orangered", "orchid", "palegoldenrod", "palegreen", "paleturquoise", "palevioletred", "papayawhip", "peachpuff",
+    "peru", "pink", "plum", "powderblue", "purple", "red", "rosybrown", "royalblue", "saddlebrown", "salmon",
+    "sandybrown", "seagreen", "seashell", "sienna", "silver", "skyblue", "slateblue", "slategray", "slategrey", "snow",
+    "springgreen", "steelblue", "tan", "teal", "thistle", "tomato", "turquoise", "violet", "wheat", "white",
+    "whitesmoke", "yellow", "yellowgreen",
+];
+
+/// A CSS3 color name, e.g. `steelblue`
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(String);
+
+impl std::str::FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Color> {
+        let name = s.trim().to_ascii_lowercase();
+        if !CSS3_COLORS.contains(&&*name) {
+            return Err(anyhow!("Expected a CSS3 color name, e.g. `steelblue`, got {s:?}"));
+        }
+        Ok(Color(name))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Color> {
+        s.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> String {
+        color.0
+    }
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_toml() {
+        let config = Config::from_toml(
+            r#"
+                drop-before = "2024-06-10"
+                organizer = "Shinbukan <mailto:info@example.org>"
+                afternoon-before = 9
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(config.drop_before, Some(DateAnchor::Date(d)) if d == NaiveDate::from_ymd_opt(2024, 6, 10).unwrap()));
+        assert_eq!(config.organizer.unwrap().to_string(), "ORGANIZER;CN=Shinbukan:mailto:info@example.org");
+        assert_eq!(config.contact, None);
+        assert_eq!(config.afternoon_before, 9);
+
+        assert!(Config::from_toml("drop-before = \"yesterday\"").is_err());
+        assert!(Config::from_toml("drop-before = \"today-7\"").is_err());
+        assert!(Config::from_toml("unknown-key = 1").is_err());
+        assert_eq!(Config::from_toml("source-tz = \"Europe/Paris\"").unwrap().source_tz, chrono_tz::Europe::Paris);
+        assert!(Config::from_toml("source-tz = \"Europe/Tokyo\"").is_err());
+    }
+
+    #[test]
+    fn to_toml() {
+        let config = Config::from_toml(
+            r#"
+                drop-before = "today-7d"
+                organizer = "Shinbukan <mailto:info@example.org>"
+                months = ["2024-06"]
+                geo = "35.5,139.25"
+                fullday-as-timed = "09:00-17:30"
+                credentials = "member:hunter2"
+                category-colors = { "稽古" = "steelblue" }
+            "#,
+        )
+        .unwrap();
+        let toml = config.to_toml();
+        assert!(toml.contains("credentials = \"member:[redacted]\"\n"));
+        assert!(!toml.contains("hunter2"));
+        // Apart from the password, the printed configuration is the same as the original one
+        let reparsed = Config::from_toml(&toml).unwrap();
+        let debug = |c: &Config| format!("{:?}", Config { now: DateTime::UNIX_EPOCH, credentials: None, ..c.clone() });
+        assert_eq!(debug(&reparsed), debug(&config));
+        assert_eq!(reparsed.credentials.unwrap().user, "member");
+        assert!(config.to_json().contains("\"credentials\": \"member:[redacted]\""));
+    }
+
+    #[test]
+    fn date_anchor() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        let resolve = |s: &str| s.parse::<DateAnchor>().unwrap().resolve(today).to_string();
+        assert_eq!(resolve("today"), "2024-06-10");
+        assert_eq!(resolve("today-7d"), "2024-06-03");
+        assert_eq!(resolve("today+30d"), "2024-07-10");
+        assert_eq!(resolve("2024-01-31"), "2024-01-31");
+        assert!("today7d".parse::<DateAnchor>().is_err());
+        assert!("today-1w".parse::<DateAnchor>().is_err());
+    }
+
+    #[test]
+    fn year_month() {
+        assert_eq!("2024-06".parse::<YearMonth>().unwrap(), YearMonth { year: 2024, month: 6 });
+        assert!("2024-13".parse::<YearMonth>().is_err());
+        assert!("2024-6".parse::<YearMonth>().is_err());
+        assert!("202406".parse::<YearMonth>().is_err());
+        assert!("2024-06-01".parse::<YearMonth>().is_err());
+    }
+
+    #[test]
+    fn geo() {
+        assert_eq!("35.6812, 139.7671".parse::<Geo>().unwrap(), Geo { lat: 35.6812, lon: 139.7671 });
+        assert!("139.7671,35.6812,0".parse::<Geo>().is_err());
+        assert!("91,0".parse::<Geo>().is_err());
+        assert!("35.6812".parse::<Geo>().is_err());
+    }
+
+    #[test]
+    fn color() {
+        assert_eq!("SteelBlue".parse::<Color>().unwrap().to_string(), "steelblue");
+        assert!("bluish".parse::<Color>().is_err());
+        assert!("#4682b4".parse::<Color>().is_err());
+
+        let config = Config::from_toml("[category-colors]\n\"自主稽古\" = \"orange\"").unwrap();
+        assert_eq!(config.category_colors["自主稽古"].to_string(), "orange");
+        assert!(Config::from_toml("[category-colors]\n\"自主稽古\" = \"bluish\"").is_err());
+    }
+
+    #[test]
+    fn locale_defaults() {
+        let config = Config::from_toml("locale = \"en\"").unwrap();
+        assert_eq!(config.all_day_keywords, ["終日", "休館", "All day", "Closed"]);
+        assert_eq!(config.locale, Locale::En);
+        // Settings given explicitly are kept, and the command line wins over the file
+        let config = Config::from_toml_with_locale("locale = \"ja\"\nall-day-keywords = [\"休み\"]", Some(Locale::En)).unwrap();
+        assert_eq!(config.all_day_keywords, ["休み"]);
+        assert_eq!(config.locale, Locale::En);
+        let config = Config::from_toml_with_locale("locale = \"en\"", Some(Locale::Ja)).unwrap();
+        assert_eq!(config.all_day_keywords, Config::default().all_day_keywords);
+        assert_eq!(Config::from_toml_with_locale("", Some(Locale::En)).unwrap().to_toml(), Config::for_locale(Locale::En).to_toml());
+        assert!(Config::from_toml("locale = \"fr\"").is_err());
+    }
+
+    #[test]
+    fn time_range() {
+        let range: TimeRange = "9:00-17:30".parse().unwrap();
+        assert_eq!((range.from.to_string(), range.to.to_string()), ("09:00:00".to_owned(), "17:30:00".to_owned()));
+        assert!("17:30-09:00".parse::<TimeRange>().is_err());
+        assert!("9-17".parse::<TimeRange>().is_err());
+    }
+
+    #[test]
+    fn extra_event() {
+        let event: ExtraEvent = "2024-07-15  海の日".parse().unwrap();
+        assert_eq!((event.date.to_string(), event.time, &*event.summary), ("2024-07-15".to_owned(), None, "海の日"));
+        let event: ExtraEvent = "2024-08-13 19:00-21:00 特別稽古".parse().unwrap();
+        assert_eq!(String::from(event), "2024-08-13 19:00-21:00 特別稽古");
+        assert_eq!("2024-08-13 2024年 納会".parse::<ExtraEvent>().unwrap().summary, "2024年 納会");
+        assert!("2024-08-13".parse::<ExtraEvent>().is_err());
+        assert!("13/08/2024 特別稽古".parse::<ExtraEvent>().is_err());
+    }
+
+    #[test]
+    fn credentials() {
+        let credentials: Credentials = "dojo:p@ss:w/rd%".parse().unwrap();
+        assert_eq!((&*credentials.user, &*credentials.password), ("dojo", "p@ss:w/rd%"));
+        assert!(!format!("{credentials:?}").contains("p@ss"));
+        assert!("dojo".parse::<Credentials>().is_err());
+    }
+
+    #[test]
+    fn organizer() {
+        let organizer: Organizer = "Shinbukan <mailto:info@example.org>".parse().unwrap();
+        assert_eq!(organizer.to_string(), "ORGANIZER;CN=Shinbukan:mailto:info@example.org");
+        let organizer: Organizer = "mailto:info@example.org".parse().unwrap();
+        assert_eq!(organizer.to_string(), "ORGANIZER:mailto:info@example.org");
+        let organizer: Organizer = "Dojo, Tokyo <mailto:info@example.org>".parse().unwrap();
+        assert_eq!(organizer.to_string(), "ORGANIZER;CN=\"Dojo, Tokyo\":mailto:info@example.org");
+        assert!("Shinbukan <info@example.org>".parse::<Organizer>().is_err());
+        assert!("mailto:not-an-address".parse::<Organizer>().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! Problems encountered while fetching or parsing a month
+
+/// Maximum length of the HTML excerpts quoted in errors
+const SNIPPET_LEN: usize = 200;
+
+/// Number of characters quoted on each side of the first character that failed to decode
+const CONTEXT_LEN: usize = 10;
+
+#[derive(thiserror::Error)]
+pub enum Error {
+    #[error("Failed to fetch the calendar page: {0:#}")]
+    Fetch(anyhow::Error),
+
+    #[error("{year:04}-{month:02} is not a valid month")]
+    InvalidMonth { year: usize, month: usize },
+
+    #[error("The page is not valid EUC-JP, with {count} invalid characters starting on line {line}, near {context:?}")]
+    Encoding { line: usize, count: usize, context: String },
+
+    #[error("Authentication likely failed, as the page contains {marker:?}")]
+    AuthFailure { marker: String },
+
+    #[error("Found no schedule table in the page titled {title:?}, which may be a login or error page")]
+    MissingTable { title: String },
+
+    #[error("Parsed day {day} twice")]
+    DuplicateDay { day: usize },
+
+    #[error("Did not parse day {day}")]
+    MissingDay { day: usize },
+
+    #[error("Found day {day} in {position}, when the month has {days_in_month} days")]
+    DayOutOfRange { day: usize, days_in_month: usize, position: CellPosition },
+
+    #[error("Could not read the time range {range:?} on day {day}")]
+    InvalidTimeRange { day: usize, range: String },
+
+    #[error("Encountered unexpected element <{tag}{attrs}> while parsing day {day}, in {position}: {cell}")]
+    UnexpectedElement {
+        day: usize,
+        tag: String,
+        attrs: String,
+        position: CellPosition,
+        cell: String,
+    },
+
+    #[error("Encountered unexpected node while parsing day {day}, in {position}: {node}")]
+    UnexpectedNode { day: usize, position: CellPosition, node: String },
+
+    #[error("Found only {count} events in total, when at least {expected} were expected")]
+    TooFewEvents { count: usize, expected: usize },
+
+    #[error("The UID {uid} is used by {count} events, which clients would show as a single one")]
+    UidCollision { uid: String, count: usize },
+}
+
+/// Oddities that are worth reporting, but do not make the result incorrect
+#[derive(thiserror::Error)]
+pub enum Warning {
+    #[error("The month has no schedule yet")]
+    BlankMonth,
+
+    #[error("The event on day {day} only has a time, and no description")]
+    EmptySummary { day: usize },
+
+    #[error("The event on day {day} is written as being on {date}")]
+    MismatchedDate { day: usize, date: String },
+
+    #[error("The events {first:?} and {second:?} on day {day} overlap")]
+    Overlap { day: usize, first: String, second: String },
+
+    #[error("No table has the expected summary, the schedule was read from the one with the most day numbers, whose summary is {summary:?}")]
+    FallbackTable { summary: String },
+
+    #[error("The event {text:?} on day {day} ends at {to}, before it starts at {from}, as only one of them was taken to be in the afternoon")]
+    EndsBeforeStart { day: usize, text: String, from: String, to: String },
+
+    #[error("The event {text:?} on day {day} only lasts {minutes} minutes, which is most likely a typo")]
+    ShortEvent { day: usize, text: String, minutes: usize },
+
+    #[error("The event {text:?} on day {day} only lasts {minutes} minutes, which is most likely a typo, and was left out")]
+    ShortEventDropped { day: usize, text: String, minutes: usize },
+
+    #[error("The page is not valid EUC-JP, with {count} invalid characters starting on line {line}, near {context:?}")]
+    Encoding { line: usize, count: usize, context: String },
+}
+
+/// Where a cell is in the page, to find it in large pages: the rank of its schedule table in the page, and its own
+/// rank among the cells of that table, both starting from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CellPosition {
+    pub table: usize,
+    pub cell: usize,
+}
+
+impl std::fmt::Display for CellPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cell {} of schedule table {}", self.cell, self.table)
+    }
+}
+
+/// Broad category of an error, telling whether a human needs to look into it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The page could not be retrieved, which is probably transient
+    Fetch,
+    /// The page did not have the expected format, which probably means that upstream changed
+    Parse,
+    /// The host rejected the credentials, which need to be fixed in the configuration
+    Credentials,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            // Corruption most likely happened on the way, rather than being in the page
+            Error::Fetch(_) | Error::Encoding { .. } => ErrorKind::Fetch,
+            // Logging in again is needed, but the calendar itself is most likely fine
+            Error::AuthFailure { .. } => ErrorKind::Credentials,
+            Error::InvalidMonth { .. }
+            | Error::MissingTable { .. }
+            | Error::DuplicateDay { .. }
+            | Error::MissingDay { .. }
+            | Error::DayOutOfRange { .. }
+            | Error::InvalidTimeRange { .. }
+            | Error::UnexpectedElement { .. }
+            | Error::UnexpectedNode { .. }
+            | Error::TooFewEvents { .. }
+            | Error::UidCollision { .. } => ErrorKind::Parse,
+        }
+    }
+
+    /// Day of the month the error is about, if any
+    pub fn day(&self) -> Option<usize> {
+        match self {
+            Error::Fetch(_)
+            | Error::InvalidMonth { .. }
+            | Error::Encoding { .. }
+            | Error::AuthFailure { .. }
+            | Error::MissingTable { .. }
+            | Error::DayOutOfRange { .. }
+            | Error::TooFewEvents { .. }
+            | Error::UidCollision { .. } => None,
+            Error::DuplicateDay { day }
+            | Error::MissingDay { day }
+            | Error::UnexpectedElement { day, .. }
+            | Error::InvalidTimeRange { day, .. }
+            | Error::UnexpectedNode { day, .. } => Some(*day),
+        }
+    }
+
+    /// Order in which errors are reported: month-wide errors first, then by day and variant
+    pub(crate) fn sort_key(&self) -> (Option<usize>, u8) {
+        let rank = match self {
+            Error::Fetch(_)
+            | Error::InvalidMonth { .. }
+            | Error::Encoding { .. }
+            | Error::AuthFailure { .. }
+            | Error::MissingTable { .. } => 0,
+            Error::UnexpectedElement { .. } => 1,
+            Error::UnexpectedNode { .. } => 2,
+            Error::MissingDay { .. } => 3,
+            Error::DuplicateDay { .. } => 4,
+            Error::TooFewEvents { .. } => 5,
+            Error::UidCollision { .. } => 6,
+            Error::DayOutOfRange { .. } => 7,
+            Error::InvalidTimeRange { .. } => 8,
+        };
+        (self.day(), rank)
+    }
+
+    pub(crate) fn unexpected_element(
+        day: usize,
+        elt: &scraper::node::Element,
+        position: CellPosition,
+        cell: &scraper::ElementRef<'_>,
+    ) -> Error {
+        let mut attrs = elt.attrs().map(|(name, value)| format!(" {name}={value:?}")).collect::<Vec<_>>();
+        attrs.sort();
+        Error::UnexpectedElement {
+            day,
+            tag: elt.name().to_owned(),
+            attrs: attrs.concat(),
+            position,
+            cell: snippet(&cell.html()),
+        }
+    }
+}
+
+// The derived Debug would dump anyhow's backtraces and the fields of each variant, make it a one-liner instead
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl Warning {
+    /// Day of the month the warning is about, if any
+    pub fn day(&self) -> Option<usize> {
+        match self {
+            Warning::BlankMonth | Warning::Encoding { .. } | Warning::FallbackTable { .. } => None,
+            Warning::EmptySummary { day }
+            | Warning::MismatchedDate { day, .. }
+            | Warning::Overlap { day, .. }
+            | Warning::EndsBeforeStart { day, .. }
+            | Warning::ShortEvent { day, .. }
+            | Warning::ShortEventDropped { day, .. } => Some(*day),
+        }
+    }
+}
+
+impl std::fmt::Debug for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+/// Find the characters that failed to decode, returning their count, the line of the first one and its surroundings
+pub(crate) fn decoding_errors(page: &str) -> Option<(usize, usize, String)> {
+    let first = page.find(char::REPLACEMENT_CHARACTER)?;
+    let count = page.matches(char::REPLACEMENT_CHARACTER).count();
+    let line = page[..first].matches('\n').count() + 1;
+    let start = page[..first].char_indices().rev().nth(CONTEXT_LEN - 1).map_or(0, |(i, _)| i);
+    let end = page[first..].char_indices().nth(CONTEXT_LEN).map_or(page.len(), |(i, _)| first + i);
+    Some((count, line, page[start..end].split_whitespace().collect::<Vec<_>>().join(" ")))
+}
+
+/// Shorten some HTML to at most `SNIPPET_LEN` characters, collapsing whitespace so that it fits on one line
+fn snippet(html: &str) -> String {
+    let collapsed = html.split_whitespace().collect::<Vec<_>>().join(" ");
+    match collapsed.char_indices().nth(SNIPPET_LEN) {
+        Some((cut, _)) => format!("{}…", &collapsed[..cut]),
+        None => collapsed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unexpected_element_shows_context() {
+        // Only one attribute, as scraper serializes them in no particular order
+        let html = scraper::Html::parse_fragment("<table><tr><td>\n3<br>\n<span class=\"x\">稽古</span>\n</td></tr></table>");
+        let cell = html.select(&scraper::Selector::parse("td").unwrap()).next().unwrap();
+        let span = html.select(&scraper::Selector::parse("span").unwrap()).next().unwrap();
+        let err = Error::unexpected_element(3, span.value(), CellPosition { table: 1, cell: 5 }, &cell);
+        assert_eq!(
+            err.to_string(),
+            r#"Encountered unexpected element <span class="x"> while parsing day 3, in cell 5 of schedule table 1: <td> 3<br> <span class="x">稽古</span> </td>"#,
+        );
+    }
+
+    #[test]
+    fn auth_failures_are_credential_errors() {
+        assert_eq!(Error::AuthFailure { marker: String::from("ログイン") }.kind(), ErrorKind::Credentials);
+        assert_eq!(Error::Fetch(anyhow::anyhow!("connection refused")).kind(), ErrorKind::Fetch);
+    }
+
+    #[test]
+    fn snippet_is_truncated_on_char_boundary() {
+        let long = "稽古".repeat(SNIPPET_LEN);
+        let short = snippet(&long);
+        assert_eq!(short.chars().count(), SNIPPET_LEN + 1);
+        assert!(short.ends_with('…'));
+    }
+}
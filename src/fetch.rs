@@ -0,0 +1,162 @@
+//! Retrieval of the raw calendar pages
+
+use crate::config::Credentials;
+use crate::Config;
+use anyhow::Context;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// URL of the calendar page for the given month, replacing `{year}` and `{month}` (zero-padded) in the template
+pub(crate) fn url_for(template: &str, year: usize, month: usize) -> String {
+    template.replace("{year}", &format!("{year:04}")).replace("{month}", &format!("{month:02}"))
+}
+
+/// Decode a calendar page read from a file, saved in EUC-JP as served by the dojo's website or in UTF-8 by browsers
+pub(crate) fn decode_page(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_owned(),
+        Err(_) => encoding_rs::EUC_JP.decode(bytes).0.into_owned(),
+    }
+}
+
+/// Source of the HTML calendar page for a given month
+pub trait CalendarFetcher {
+    fn fetch(&self, year: usize, month: usize) -> impl Future<Output = anyhow::Result<String>> + Send;
+
+    /// Whether the page last fetched for the month came from a mirror, which may lag behind the dojo's website
+    fn served_by_fallback(&self, _year: usize, _month: usize) -> bool {
+        false
+    }
+}
+
+/// Fetches the calendar pages from the dojo's website, or from its mirror if the website fails
+#[derive(Clone, Debug)]
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    url_template: String,
+    fallback_url_template: Option<String>,
+    credentials: Option<Credentials>,
+    max_body_bytes: usize,
+    /// Months whose page came from `fallback_url_template`, shared between the clones
+    served_by_fallback: Arc<Mutex<HashSet<(usize, usize)>>>,
+}
+
+impl HttpFetcher {
+    pub fn new(config: &Config) -> HttpFetcher {
+        let mut client = reqwest::Client::builder();
+        if config.http1_only {
+            client = client.http1_only();
+        }
+        // Without idle connections in the pool, each request opens a new one
+        if config.no_keepalive {
+            client = client.pool_max_idle_per_host(0);
+        }
+        if let Some(secs) = config.pool_idle_timeout_secs {
+            client = client.pool_idle_timeout(std::time::Duration::from_secs(secs));
+        }
+        for (host, addr) in &config.resolve {
+            // reqwest ignores the port, and uses the one of the URL
+            client = client.resolve(host, std::net::SocketAddr::new(*addr, 0));
+        }
+        HttpFetcher {
+            // Only fails if the TLS backend cannot be initialized, as `reqwest::Client::new` would
+            client: client.build().expect("Failed to initialize the HTTP client"),
+            url_template: config.url_template.clone(),
+            fallback_url_template: config.fallback_url_template.clone(),
+            credentials: config.credentials.clone(),
+            max_body_bytes: config.max_body_bytes,
+            served_by_fallback: Arc::default(),
+        }
+    }
+
+    async fn fetch_from(&self, url: &str, credentials: Option<&Credentials>) -> anyhow::Result<String> {
+        tracing::debug!(%url, "fetching calendar page");
+        let mut req = self.client.get(url);
+        // Sent as a header rather than in the URL, so that they need no escaping and do not end up in logs
+        if let Some(credentials) = credentials {
+            req = req.basic_auth(&credentials.user, Some(&credentials.password));
+        }
+        let mut resp = req.send().await?.error_for_status()?;
+        // Read chunk by chunk, so that a huge body is given up on without being held in memory
+        let too_large = || anyhow::anyhow!("The page is larger than the maximum of {} bytes", self.max_body_bytes);
+        if resp.content_length().is_some_and(|len| len > self.max_body_bytes as u64) {
+            return Err(too_large());
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if bytes.len() + chunk.len() > self.max_body_bytes {
+                return Err(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        let text = encoding_rs::EUC_JP.decode(&bytes).0.into_owned();
+        // A connection dropped halfway through gives the start of the page, whose last days would be reported as missing
+        if !text.to_ascii_lowercase().contains("</html>") {
+            anyhow::bail!("The page stops after {} bytes, before its closing </html>, most likely as the connection dropped", bytes.len());
+        }
+        Ok(text)
+    }
+}
+
+impl CalendarFetcher for HttpFetcher {
+    async fn fetch(&self, year: usize, month: usize) -> anyhow::Result<String> {
+        let url = url_for(&self.url_template, year, month);
+        let err = match self.fetch_from(&url, self.credentials.as_ref()).await {
+            Ok(text) => {
+                tracing::info!(%url, "{year:04}-{month:02} served by the primary source");
+                self.served_by_fallback.lock().unwrap().remove(&(year, month));
+                return Ok(text);
+            }
+            Err(err) => err,
+        };
+        let Some(fallback_template) = &self.fallback_url_template else {
+            return Err(err);
+        };
+        let fallback_url = url_for(fallback_template, year, month);
+        tracing::warn!(%url, %fallback_url, "primary source failed, trying the fallback: {err:#}");
+        // The mirror is likely a third party, which must not see the credentials
+        match self.fetch_from(&fallback_url, None).await {
+            Ok(text) => {
+                tracing::info!(url = %fallback_url, "{year:04}-{month:02} served by the fallback source");
+                self.served_by_fallback.lock().unwrap().insert((year, month));
+                Ok(text)
+            }
+            Err(fallback_err) => Err(fallback_err.context(format!("Fallback after primary source failed: {err:#}"))),
+        }
+    }
+
+    fn served_by_fallback(&self, year: usize, month: usize) -> bool {
+        self.served_by_fallback.lock().unwrap().contains(&(year, month))
+    }
+}
+
+/// Reads pages named `YYYY-MM.html` from a directory, e.g. captured fixtures, in UTF-8 or EUC-JP
+#[derive(Clone, Debug)]
+pub struct DirFetcher {
+    dir: PathBuf,
+}
+
+impl DirFetcher {
+    pub fn new(dir: impl Into<PathBuf>) -> DirFetcher {
+        DirFetcher { dir: dir.into() }
+    }
+}
+
+impl CalendarFetcher for DirFetcher {
+    async fn fetch(&self, year: usize, month: usize) -> anyhow::Result<String> {
+        let path = self.dir.join(format!("{year:04}-{month:02}.html"));
+        let bytes = tokio::fs::read(&path).await.with_context(|| format!("Reading calendar page {path:?}"))?;
+        Ok(decode_page(&bytes))
+    }
+}
+
+/// Serves already-decoded pages from memory, keyed by (year, month)
+impl CalendarFetcher for HashMap<(usize, usize), String> {
+    async fn fetch(&self, year: usize, month: usize) -> anyhow::Result<String> {
+        self.get(&(year, month))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No calendar page for {year:04}-{month:02}"))
+    }
+}
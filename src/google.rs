@@ -0,0 +1,302 @@
+//! Synchronization of the events into a Google Calendar, for members who do not subscribe to ICS files
+//!
+//! Events are matched by their `iCalUID`, which is the same stable UID as in the ICS output. Only events that were
+//! created by this tool, as marked by a private extended property, are ever updated or deleted.
+
+use crate::{is_published, suffixed_uid, to_utc, Config, EventTime, MonthResult, UidCollision};
+use anyhow::Context;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use std::collections::BTreeMap;
+
+const API_URL: &str = "https://www.googleapis.com/calendar/v3";
+
+/// Private extended property marking the events managed by this tool
+const MANAGED_PROPERTY: &str = "shinbukan-ics";
+
+/// An event, in the format of the Google Calendar API
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleEvent {
+    #[serde(rename = "iCalUID")]
+    pub ical_uid: String,
+    #[serde(default)]
+    pub summary: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub start: GoogleTime,
+    pub end: GoogleTime,
+}
+
+/// Start or end of an event: a date for all-day events, a time otherwise
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GoogleTime {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<NaiveDate>,
+    /// Google returns times in the calendar's own offset, parsing them into UTC makes them comparable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_time: Option<DateTime<Utc>>,
+}
+
+/// An event already in the calendar, along with the identifier the API knows it by
+#[derive(Debug, serde::Deserialize)]
+struct ExistingEvent {
+    id: String,
+    #[serde(flatten)]
+    event: GoogleEvent,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventList {
+    #[serde(default)]
+    items: Vec<ExistingEvent>,
+    next_page_token: Option<String>,
+}
+
+/// A change to bring the calendar in line with the schedule
+#[derive(Debug, PartialEq)]
+pub enum Operation {
+    Insert(GoogleEvent),
+    Update { id: String, event: GoogleEvent },
+    Delete { id: String, ical_uid: String },
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Insert(event) => write!(f, "insert {} {:?} on {}", event.ical_uid, event.summary, event.start),
+            Operation::Update { event, .. } => write!(f, "update {} {:?} on {}", event.ical_uid, event.summary, event.start),
+            Operation::Delete { ical_uid, .. } => write!(f, "delete {ical_uid}"),
+        }
+    }
+}
+
+impl std::fmt::Display for GoogleTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.date, self.date_time) {
+            (_, Some(time)) => write!(f, "{}", time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            (Some(date), None) => write!(f, "{date}"),
+            (None, None) => f.write_str("an unknown date"),
+        }
+    }
+}
+
+impl GoogleTime {
+    /// Day of the event in the timezone of the dojo
+    fn local_date(&self, tz: chrono_tz::Tz) -> Option<NaiveDate> {
+        self.date.or_else(|| Some(self.date_time?.with_timezone(&tz).date_naive()))
+    }
+}
+
+/// The events of the schedule, as they should be in the calendar
+///
+/// The calendar holds a single event per iCalUID, so events colliding with a previous one are suffixed as in the ICS
+/// output with `on_uid_collision = "suffix"`, and left out otherwise.
+fn desired_events(results: &[MonthResult], config: &Config) -> Vec<GoogleEvent> {
+    let domain = config.uid_domain.trim_start_matches('@');
+    let mut seen = BTreeMap::<String, usize>::new();
+    let mut events = Vec::new();
+    for r in results {
+        for e in r.published_events(config) {
+            let date = r.date_of(e);
+            let (start, end) = match &e.time {
+                EventTime::Timed { from, to } => (
+                    GoogleTime { date: None, date_time: Some(to_utc(config.source_tz, date, from)) },
+                    GoogleTime { date: None, date_time: Some(to_utc(config.source_tz, date, to)) },
+                ),
+                // The API wants the exclusive end date of all-day events
+                EventTime::FullDay => (
+                    GoogleTime { date: Some(date), date_time: None },
+                    GoogleTime { date: date.succ_opt(), date_time: None },
+                ),
+            };
+            let mut ical_uid = e.uid(domain);
+            let rank = seen.entry(ical_uid.clone()).or_default();
+            *rank += 1;
+            match (*rank, config.on_uid_collision) {
+                (1, _) => (),
+                (rank, UidCollision::Suffix) => ical_uid = suffixed_uid(&ical_uid, rank),
+                (_, UidCollision::Error | UidCollision::Ignore) => continue,
+            }
+            events.push(GoogleEvent {
+                ical_uid,
+                summary: e.text.clone(),
+                location: e.location.clone(),
+                description: Some(e.description.join("\n")).filter(|d| !d.is_empty()),
+                start,
+                end,
+            });
+        }
+    }
+    events
+}
+
+/// The operations that bring the `existing` events in line with the parsed schedule
+///
+/// Events that are no longer in the schedule are only deleted from months that were parsed without errors, and that
+/// are published as per `drop_before` and `horizon_days`: past events stay in the calendar once out of the window.
+pub fn plan(results: &[MonthResult], config: &Config, existing: Vec<(String, GoogleEvent)>) -> Vec<Operation> {
+    let desired = desired_events(results, config);
+    let deletable = |event: &GoogleEvent| {
+        let Some(date) = event.start.local_date(config.source_tz) else {
+            return false;
+        };
+        let clean = results.iter().any(|r| (r.year, r.month) == (date.year() as usize, date.month() as usize) && r.errors.is_empty());
+        clean && is_published(date, config)
+    };
+    let mut existing = existing.into_iter().map(|(id, e)| (e.ical_uid.clone(), (id, e))).collect::<BTreeMap<_, _>>();
+    let mut ops = Vec::new();
+    for event in desired {
+        match existing.remove(&event.ical_uid) {
+            None => ops.push(Operation::Insert(event)),
+            Some((_, current)) if current == event => (),
+            Some((id, _)) => ops.push(Operation::Update { id, event }),
+        }
+    }
+    let deleted = existing.into_values().filter(|(_, e)| deletable(e));
+    ops.extend(deleted.map(|(id, e)| Operation::Delete { id, ical_uid: e.ical_uid }));
+    ops
+}
+
+/// Client for the events of one Google calendar
+#[derive(Clone, Debug)]
+pub struct GoogleCalendar {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+impl GoogleCalendar {
+    /// Access the calendar through the Google Calendar API, with an OAuth access token
+    pub fn new(calendar_id: &str, token: &str) -> GoogleCalendar {
+        GoogleCalendar::with_api_url(API_URL, calendar_id, token)
+    }
+
+    /// Access the calendar through another endpoint implementing the same API, e.g. a test server
+    pub fn with_api_url(api_url: &str, calendar_id: &str, token: &str) -> GoogleCalendar {
+        let calendar_id = calendar_id.replace('%', "%25").replace('/', "%2F").replace('#', "%23").replace('?', "%3F");
+        GoogleCalendar {
+            client: reqwest::Client::new(),
+            base_url: format!("{}/calendars/{calendar_id}/events", api_url.trim_end_matches('/')),
+            token: token.to_owned(),
+        }
+    }
+
+    /// The events managed by this tool, along with their identifiers
+    pub async fn managed_events(&self) -> anyhow::Result<Vec<(String, GoogleEvent)>> {
+        let mut events = Vec::new();
+        let mut page_token = None;
+        loop {
+            let mut query = vec![("privateExtendedProperty", format!("{MANAGED_PROPERTY}=1")), ("maxResults", String::from("2500"))];
+            if let Some(token) = page_token.take() {
+                query.push(("pageToken", token));
+            }
+            let resp = self.client.get(&self.base_url).bearer_auth(&self.token).query(&query).send().await?;
+            let list: EventList = resp.error_for_status()?.json().await.context("Parsing the list of events")?;
+            events.extend(list.items.into_iter().map(|e| (e.id, e.event)));
+            match list.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => return Ok(events),
+            }
+        }
+    }
+
+    pub async fn apply(&self, op: &Operation) -> anyhow::Result<()> {
+        let req = match op {
+            // Importing, rather than inserting, is what lets the event keep our iCalUID
+            Operation::Insert(event) => self.client.post(format!("{}/import", self.base_url)).json(&managed(event)),
+            Operation::Update { id, event } => self.client.put(format!("{}/{id}", self.base_url)).json(&managed(event)),
+            Operation::Delete { id, .. } => self.client.delete(format!("{}/{id}", self.base_url)),
+        };
+        req.bearer_auth(&self.token).send().await?.error_for_status().with_context(|| format!("Failed to {op}"))?;
+        Ok(())
+    }
+}
+
+/// The body of a request creating or updating the event, marked as managed by this tool
+fn managed(event: &GoogleEvent) -> serde_json::Value {
+    let mut body = serde_json::to_value(event).unwrap();
+    body["extendedProperties"] = serde_json::json!({ "private": { MANAGED_PROPERTY: "1" } });
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_calendar, Error};
+    use std::collections::BTreeSet;
+
+    fn june() -> MonthResult {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+        result
+    }
+
+    #[test]
+    fn desired() {
+        let events = desired_events(&[june()], &Config::default());
+        assert_eq!(events.len(), 13);
+        assert_eq!(
+            serde_json::to_value(&events[0]).unwrap(),
+            serde_json::json!({
+                "iCalUID": events[0].ical_uid,
+                "summary": "鳴尾浜 柔道場/自主稽古",
+                "start": { "dateTime": "2024-06-02T04:00:00Z" },
+                "end": { "dateTime": "2024-06-02T08:00:00Z" },
+            }),
+        );
+    }
+
+    #[test]
+    fn plan_changes() {
+        let config = Config::default();
+        let desired = desired_events(&[june()], &config);
+        let mut changed = desired[1].clone();
+        changed.summary = String::from("休み");
+        let mut gone = desired[2].clone();
+        gone.ical_uid = String::from("gone@shinbukan-ics");
+        // Google gives times in the calendar's offset, which are still the same times
+        let mut same = desired[0].clone();
+        same.start.date_time = Some("2024-06-02T13:00:00+09:00".parse().unwrap());
+        let mut outside = desired[3].clone();
+        outside.ical_uid = String::from("past@shinbukan-ics");
+        outside.start.date_time = Some("2024-05-02T13:00:00+09:00".parse().unwrap());
+        let existing = [("a", same), ("b", changed), ("c", gone), ("d", outside)].map(|(id, e)| (String::from(id), e));
+
+        let ops = plan(&[june()], &config, existing.to_vec());
+        assert_eq!(ops[0], Operation::Update { id: String::from("b"), event: desired[1].clone() });
+        assert_eq!(ops[1].to_string(), format!("insert {} {:?} on 2024-06-08T04:00:00Z", desired[2].ical_uid, desired[2].summary));
+        assert_eq!(ops.iter().filter(|op| matches!(op, Operation::Insert(_))).count(), 11);
+        // Events of months that were not fetched stay in place
+        assert_eq!(ops.last().unwrap(), &Operation::Delete { id: String::from("c"), ical_uid: String::from("gone@shinbukan-ics") });
+        assert_eq!(ops.last().unwrap().to_string(), "delete gone@shinbukan-ics");
+        assert_eq!(ops.len(), 13);
+
+        // Nor are events deleted from months that failed
+        let mut failed = june();
+        failed.error(Error::MissingDay { day: 3 });
+        let ops = plan(&[failed], &config, existing.to_vec());
+        assert!(!ops.iter().any(|op| matches!(op, Operation::Delete { .. })));
+    }
+
+    #[test]
+    fn colliding_uids() {
+        // The same line on the same day of two months gives the same UID
+        let mut july = MonthResult::new(2024, 7);
+        parse_calendar(&mut july, include_str!("fixtures/2024-06.html"), &Config::default());
+        let results = [june(), july];
+        let uids = |events: &[GoogleEvent]| events.iter().map(|e| e.ical_uid.clone()).collect::<BTreeSet<_>>();
+
+        let suffixed = desired_events(&results, &Config::default());
+        assert_eq!(suffixed.len(), 26);
+        assert_eq!(uids(&suffixed).len(), 26);
+        assert!(suffixed[13].ical_uid.ends_with("-dup2@shinbukan-ics"));
+
+        let config = Config { on_uid_collision: UidCollision::Ignore, ..Config::default() };
+        let ops = plan(&results, &config, Vec::new());
+        assert_eq!(ops, desired_events(&[june()], &config).into_iter().map(Operation::Insert).collect::<Vec<_>>());
+    }
+}
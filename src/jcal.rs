@@ -0,0 +1,164 @@
+//! Conversion of the generated iCalendar data to jCal, its JSON representation from RFC 7265
+//!
+//! The conversion works on the rendered ICS, so that both formats always carry the same properties.
+
+use serde_json::{json, Map, Value};
+
+/// Convert ICS content lines into jCal, as a single component or as an array of them for fragments
+pub(crate) fn from_ics(ics: &str) -> String {
+    // Components being built, innermost last, along with their name
+    let mut stack: Vec<(String, Vec<Value>, Vec<Value>)> = Vec::new();
+    let mut top = Vec::new();
+    // jCal values are never folded, unlike the long lines of the ICS
+    let ics = ics.replace("\n ", "");
+    for line in ics.lines().filter(|l| !l.is_empty()) {
+        let (name, params, value) = split_line(line);
+        match name.as_str() {
+            "begin" => stack.push((value.to_ascii_lowercase(), Vec::new(), Vec::new())),
+            "end" => {
+                let Some((name, properties, components)) = stack.pop() else {
+                    continue;
+                };
+                let component = json!([name, properties, components]);
+                match stack.last_mut() {
+                    Some((_, _, components)) => components.push(component),
+                    None => top.push(component),
+                }
+            }
+            _ => {
+                if let Some((_, properties, _)) = stack.last_mut() {
+                    properties.push(property(&name, params, value));
+                }
+            }
+        }
+    }
+    let jcal = match <[Value; 1]>::try_from(top) {
+        Ok([component]) => component,
+        Err(top) => Value::Array(top),
+    };
+    serde_json::to_string_pretty(&jcal).unwrap()
+}
+
+/// Split a content line into its lowercase name, its parameters and its raw value
+fn split_line(line: &str) -> (String, Map<String, Value>, &str) {
+    let mut params = Map::new();
+    let name_end = line.find([';', ':']).unwrap_or(line.len());
+    let name = line[..name_end].to_ascii_lowercase();
+    let mut rest = &line[name_end..];
+    while let Some(param) = rest.strip_prefix(';') {
+        // Parameter values may be quoted, and then contain separators
+        let (key, after) = param.split_once('=').unwrap_or((param, ""));
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..end], &quoted[(end + 1).min(quoted.len())..])
+            }
+            None => after.split_at(after.find([';', ':']).unwrap_or(after.len())),
+        };
+        params.insert(key.to_ascii_lowercase(), Value::String(value.to_owned()));
+        rest = after;
+    }
+    (name, params, rest.strip_prefix(':').unwrap_or(rest))
+}
+
+/// A jCal property, typed as RFC 7265 says for the properties we emit
+fn property(name: &str, mut params: Map<String, Value>, value: &str) -> Value {
+    // The type goes in its own field rather than in a parameter
+    let explicit = params.remove("value").and_then(|v| v.as_str().map(str::to_ascii_lowercase));
+    let kind = match (name, explicit.as_deref()) {
+        (_, Some("date")) => "date",
+        (_, Some("uri")) => "uri",
+        ("dtstart" | "dtend" | "dtstamp" | "rdate", _) => "date-time",
+        ("url" | "tzurl" | "attach", _) => "uri",
+        ("organizer", _) => "cal-address",
+        ("tzoffsetfrom" | "tzoffsetto", _) => "utc-offset",
+        ("freebusy", _) => "period",
+        ("version" | "prodid" | "uid" | "summary" | "description" | "location" | "contact" | "categories" | "color" | "name", _) => "text",
+        ("tzid" | "tzname", _) => "text",
+        // Not in any RFC, but widely known to be text
+        ("x-wr-calname" | "x-wr-caldesc" | "x-alt-desc", _) => "text",
+        _ => "unknown",
+    };
+    let value = match kind {
+        "date" if value.len() == 8 && value.is_ascii() => format!("{}-{}-{}", &value[..4], &value[4..6], &value[6..8]),
+        "date-time" => date_time(value),
+        "period" => value.split('/').map(date_time).collect::<Vec<_>>().join("/"),
+        "utc-offset" if value.len() == 5 && value.is_ascii() => format!("{}:{}", &value[..3], &value[3..]),
+        "text" => unescape(value),
+        _ => value.to_owned(),
+    };
+    json!([name, params, kind, value])
+}
+
+/// Convert a `YYYYMMDDTHHMMSSZ` basic format date-time to the extended format used by jCal
+fn date_time(value: &str) -> String {
+    if value.len() < 15 || !value.is_ascii() {
+        return value.to_owned();
+    }
+    let (date, time) = (&value[..8], &value[9..15]);
+    format!(
+        "{}-{}-{}T{}:{}:{}{}",
+        &date[..4],
+        &date[4..6],
+        &date[6..8],
+        &time[..2],
+        &time[2..4],
+        &time[4..6],
+        &value[15..],
+    )
+}
+
+/// Undo the escaping of TEXT values, as jCal carries them as plain JSON strings
+fn unescape(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => res.push('\n'),
+            Some(escaped) => res.push(escaped),
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_calendar, render_calendar, Config, MonthResult, OutputFormat};
+
+    #[test]
+    fn jcal() {
+        let mut result = MonthResult::new(2024, 7);
+        parse_calendar(&mut result, include_str!("fixtures/2024-07.html"), &Config::default());
+        let config = Config { format: OutputFormat::Jcal, ..Config::default() };
+        insta::assert_snapshot!(render_calendar(&[result], &config));
+    }
+
+    #[test]
+    fn lines() {
+        let property = |line| {
+            let (name, params, value) = split_line(line);
+            super::property(&name, params, value)
+        };
+        assert_eq!(
+            property(r#"ORGANIZER;CN="Shinbukan: dojo":mailto:info@example.org"#),
+            json!(["organizer", { "cn": "Shinbukan: dojo" }, "cal-address", "mailto:info@example.org"]),
+        );
+        assert_eq!(property("DTSTART;VALUE=DATE:20240601"), json!(["dtstart", {}, "date", "2024-06-01"]));
+        assert_eq!(property("DTEND:20240601T120000Z"), json!(["dtend", {}, "date-time", "2024-06-01T12:00:00Z"]));
+        assert_eq!(property("RDATE:20240608T120000Z"), json!(["rdate", {}, "date-time", "2024-06-08T12:00:00Z"]));
+        assert_eq!(property(r"SUMMARY:a\, b\; c\\d\ne"), json!(["summary", {}, "text", "a, b; c\\d\ne"]));
+        assert_eq!(property(r"X-WR-CALDESC:a\, b"), json!(["x-wr-caldesc", {}, "text", "a, b"]));
+        assert_eq!(property("TZOFFSETTO:+0530"), json!(["tzoffsetto", {}, "utc-offset", "+05:30"]));
+        assert_eq!(
+            property("DTSTART;TZID=Europe/Paris:20240331T100000"),
+            json!(["dtstart", { "tzid": "Europe/Paris" }, "date-time", "2024-03-31T10:00:00"]),
+        );
+        assert_eq!(property(r"X-UNKNOWN:a\, b"), json!(["x-unknown", {}, "unknown", r"a\, b"]));
+    }
+}
@@ -0,0 +1,2296 @@
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Utc, Months, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Timelike};
+use futures::StreamExt;
+use scraper::Node;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::LazyLock;
+
+mod config;
+mod error;
+mod fetch;
+pub mod google;
+mod jcal;
+mod output;
+mod state;
+mod status;
+mod timezone;
+
+pub use config::{Color, Config, Credentials, DateAnchor, ExtraEvent, FinalNewline, Geo, Locale, Organizer, OutputFormat, ShortEvents, SortBy, TimeRange, UidCollision, YearMonth};
+pub use error::{CellPosition, Error, ErrorKind, Warning};
+pub use fetch::{CalendarFetcher, DirFetcher, HttpFetcher};
+pub use output::{checksum_path, merge_into, merge_into_file, write_atomically, write_calendar};
+pub use state::State;
+pub use status::{summary_table, Status};
+
+use fetch::{decode_page, url_for};
+use output::Calendar;
+
+/// Start of the UID of the month summary events, which is followed by the month
+const MONTH_SUMMARY_UID: &str = "month-summary-";
+
+/// Subdomain of `uid_domain` that the UIDs of `extra_events` are in, as they are not counted as events of the pages
+const EXTRA_EVENTS_SUBDOMAIN: &str = "extra";
+
+/// Radius in meters of the map pin shown by Apple clients around the event's coordinates
+const APPLE_LOCATION_RADIUS: usize = 70;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Time {
+    hours: usize,
+    minutes: usize,
+}
+
+impl std::fmt::Display for Time {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.hours, self.minutes)
+    }
+}
+
+#[derive(Debug, Hash)]
+enum EventTime {
+    Timed {
+        from: Time,
+        to: Time,
+    },
+    FullDay,
+}
+
+#[derive(Debug)]
+struct Event {
+    day: usize,
+    time: EventTime,
+    text: String,
+    location: Option<String>,
+    description: Vec<String>,
+    /// Index of the event among those split from a single line with several time ranges, starting from 1
+    part: Option<usize>,
+    /// Links to PDF flyers, as written in the page
+    attachments: Vec<String>,
+    /// Parts of the text that were emphasized or linked in the page
+    markup: Vec<Markup>,
+    /// UID instead of the one hashed from the event, for those that are not read from the page
+    uid_override: Option<String>,
+    /// URL instead of the page's, `Some(None)` leaving it out for events that do not come from the page
+    url_override: Option<Option<String>>,
+}
+
+/// A part of an event's text that the page sets apart, kept for its HTML description
+#[derive(Clone, Debug, PartialEq)]
+enum Markup {
+    /// Written in red, as the page does for notes like cancellations
+    Emphasis(String),
+    Link { text: String, href: String },
+}
+
+impl Event {
+    fn append(&mut self, append: &str) {
+        self.text.push(' ');
+        self.text.push_str(append);
+    }
+
+    /// Hash identifying the event, used for its UID
+    fn uid_hash(&self) -> u64 {
+        // Hash the same data in the same order as the `Hash` derive did back when `Event` was an enum of timed and
+        // full-day events, so that UIDs stay stable
+        let mut hasher = std::hash::DefaultHasher::new();
+        match &self.time {
+            EventTime::Timed { from, to } => {
+                0isize.hash(&mut hasher);
+                self.day.hash(&mut hasher);
+                from.hash(&mut hasher);
+                to.hash(&mut hasher);
+            }
+            EventTime::FullDay => {
+                1isize.hash(&mut hasher);
+                self.day.hash(&mut hasher);
+            }
+        }
+        self.text.hash(&mut hasher);
+        // Data added since then is only hashed when set, to keep the UIDs of events without it unchanged
+        if let Some(location) = &self.location {
+            location.hash(&mut hasher);
+        }
+        if !self.description.is_empty() {
+            self.description.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// UID of the event, shared by the parts of a line with several time ranges up to a suffix
+    fn uid(&self, domain: &str) -> String {
+        if let Some(uid) = &self.uid_override {
+            return uid.clone();
+        }
+        let Some(part) = self.part else {
+            return format!("{}@{domain}", self.uid_hash());
+        };
+        let mut hasher = std::hash::DefaultHasher::new();
+        2isize.hash(&mut hasher);
+        self.day.hash(&mut hasher);
+        self.text.hash(&mut hasher);
+        self.location.hash(&mut hasher);
+        format!("{}-{part}@{domain}", hasher.finish())
+    }
+
+    /// HTML version of the text and description, with the emphasis and links that the text had in the page
+    fn html_description(&self, description: &[String], url: &str) -> String {
+        // Byte ranges of the text to mark up, along with their tags
+        let mut spans = Vec::new();
+        for markup in &self.markup {
+            let (part, open, close) = match markup {
+                Markup::Emphasis(text) => (text, String::from("<strong style=\"color:red\">"), "</strong>"),
+                Markup::Link { text, href } => (text, format!("<a href=\"{}\">", escape_html(&resolve_link(url, href))), "</a>"),
+            };
+            if let Some(start) = self.text.find(part.as_str()).filter(|_| !part.is_empty()) {
+                spans.push((start, start + part.len(), open, close));
+            }
+        }
+        spans.sort_by_key(|(start, ..)| *start);
+        let mut html = String::from("<html><body><p>");
+        let mut done = 0;
+        for (start, end, open, close) in spans {
+            // Overlapping parts, like a red note within a link, only get the first markup
+            if start < done {
+                continue;
+            }
+            html.push_str(&escape_html(&self.text[done..start]));
+            html.push_str(&open);
+            html.push_str(&escape_html(&self.text[start..end]));
+            html.push_str(close);
+            done = end;
+        }
+        html.push_str(&escape_html(&self.text[done..]));
+        html.push_str("</p>");
+        if !description.is_empty() {
+            let lines = description.iter().map(|l| escape_html(l)).collect::<Vec<_>>();
+            html.push_str(&format!("<p>{}</p>", lines.join("<br>")));
+        }
+        html.push_str("</body></html>");
+        html
+    }
+
+    fn as_ics(&self, month_result: &MonthResult, config: &Config) -> String {
+        let (year, month) = (month_result.year, month_result.month);
+        let url = url_for(&config.url_template, year, month);
+        let uid = self.uid(config.uid_domain.trim_start_matches('@'));
+        let mut description = self.description.clone();
+        let text = match config.max_summary_len.and_then(|max| self.text.char_indices().nth(max)) {
+            Some((cut, _)) => {
+                // Keep the full text around, so that nothing is lost to the truncation
+                description.insert(0, self.text.clone());
+                escape_text(&format!("{}…", &self.text[..cut]))
+            }
+            None => escape_text(&self.text),
+        };
+        let mut text = match config.weekday_prefix {
+            Some(locale) => format!("({}) {text}", locale.weekday(month_result.date_of(self).weekday())),
+            None => text,
+        };
+        if let (true, EventTime::Timed { from, to }) = (config.timezone_output_display, &self.time) {
+            // Show the times as written on the page next to the UTC ones, to make the conversion easy to check
+            let zone = to_utc(config.source_tz, month_result.date_of(self), from).with_timezone(&config.source_tz).format("%Z");
+            text.push_str(&format!(" ({zone} {:02}:{:02}-{:02}:{:02})", from.hours, from.minutes, to.hours, to.minutes));
+        }
+        // Clients that handle all-day events poorly can get them as timed events instead
+        let fullday_as_timed = config.fullday_as_timed.map(|range| EventTime::Timed {
+            from: Time { hours: range.from.hour() as usize, minutes: range.from.minute() as usize },
+            to: Time { hours: range.to.hour() as usize, minutes: range.to.minute() as usize },
+        });
+        let time = match (&self.time, &fullday_as_timed) {
+            (EventTime::FullDay, Some(timed)) => timed,
+            (time, _) => time,
+        };
+        let (start, end) = match time {
+            EventTime::FullDay => {
+                let day = format!("DATE:{year:04}{month:02}{:02}", self.day);
+                (format!("DTSTART;VALUE={day}"), format!("DTEND;VALUE={day}"))
+            }
+            EventTime::Timed { from, to } => {
+                let date = month_result.date_of(self);
+                let (from, to) = (to_utc(config.source_tz, date, from), to_utc(config.source_tz, date, to));
+                match config.display_tz {
+                    Some(tz) => {
+                        let local = |t: DateTime<Utc>| t.with_timezone(&tz).format("%Y%m%dT%H%M%S");
+                        (format!("DTSTART;TZID={}:{}", tz.name(), local(from)), format!("DTEND;TZID={}:{}", tz.name(), local(to)))
+                    }
+                    None => (format!("{}", from.format("DTSTART:%Y%m%dT%H%M%SZ")), format!("{}", to.format("DTEND:%Y%m%dT%H%M%SZ"))),
+                }
+            }
+        };
+        let mut extra = String::new();
+        if let Some(location) = &self.location {
+            extra.push_str(&format!("LOCATION:{}\n", escape_text(location)));
+            if let Some(geo) = config.geo.filter(|_| config.apple_location) {
+                // Parameter values are quoted, and thus cannot contain quotes
+                let location = location.replace('"', "");
+                extra.push_str(&format!(
+                    "X-APPLE-STRUCTURED-LOCATION;VALUE=URI;X-ADDRESS=\"{location}\";X-APPLE-RADIUS={APPLE_LOCATION_RADIUS};X-TITLE=\"{location}\":geo:{},{}\n",
+                    geo.lat, geo.lon,
+                ));
+            }
+        }
+        if let Some(organizer) = &config.organizer {
+            extra.push_str(&format!("{organizer}\n"));
+        }
+        if let Some(contact) = &config.contact {
+            extra.push_str(&format!("CONTACT:{}\n", escape_text(contact)));
+        }
+        // The most specific keyword wins, e.g. 自主稽古 over 稽古
+        let category = config.category_colors.iter().filter(|(k, _)| self.text.contains(k.as_str())).max_by_key(|(k, _)| k.len());
+        if let Some((category, _)) = category {
+            extra.push_str(&format!("CATEGORIES:{}\n", escape_text(category)));
+        }
+        if let Some(color) = category.map(|(_, c)| c).or(config.event_color.as_ref()) {
+            extra.push_str(&format!("COLOR:{color}\n"));
+        }
+        if config.provenance && self.url_override.is_none() {
+            description.push(format!("Source: {url}"));
+            if let Some(fetched_at) = month_result.fetched_at {
+                description.push(format!("Fetched: {}", fetched_at.to_rfc3339_opts(SecondsFormat::Secs, true)));
+            }
+        }
+        let html = match config.html_description && !self.markup.is_empty() {
+            true => {
+                // The full text of a truncated summary already is the first paragraph
+                let lines = description.strip_prefix(std::slice::from_ref(&self.text)).unwrap_or(&description);
+                Some(self.html_description(lines, &url))
+            }
+            false => None,
+        };
+        if html.is_some() {
+            // Keep the links in the plain version too, as it is all that most clients show
+            for markup in &self.markup {
+                if let Markup::Link { text, href } = markup {
+                    description.push(format!("{text}: {}", resolve_link(&url, href)));
+                }
+            }
+        }
+        if !description.is_empty() {
+            extra.push_str(&format!("DESCRIPTION:{}\n", escape_text(&description.join("\n"))));
+        }
+        if let Some(html) = html {
+            extra.push_str(&format!("X-ALT-DESC;FMTTYPE=text/html:{}\n", escape_text(&html)));
+        }
+        for attachment in &self.attachments {
+            extra.push_str(&format!("ATTACH;FMTTYPE=application/pdf:{}\n", resolve_link(&url, attachment)));
+        }
+        if config.source_month {
+            extra.push_str(&format!("X-SHINBUKAN-SOURCE-MONTH:{year:04}{month:02}\n"));
+        }
+        let url_property = match (&self.url_override, &config.event_url, config.no_url_property) {
+            (_, _, true) | (Some(None), _, _) => String::new(),
+            (Some(Some(url)), _, false) => format!("URL:{url}\n"),
+            (None, Some(template), false) => {
+                format!("URL:{}\n", url_for(template, year, month).replace("{day}", &format!("{:02}", self.day)))
+            }
+            (None, None, false) => format!("URL:{url}\n"),
+        };
+        let now = dtstamp(config);
+        // Only the properties that make the event are kept, however many others were asked for
+        let (url_property, extra) = match config.compact {
+            true => (String::new(), String::new()),
+            false => (url_property, extra),
+        };
+
+        format!(
+            "BEGIN:VEVENT\n\
+             UID:{uid}\n\
+             DTSTAMP:{now}\n\
+             {start}\n\
+             {end}\n\
+             SUMMARY:{text}\n\
+             {url_property}\
+             {extra}\
+             END:VEVENT\n"
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct MonthResult {
+    year: usize,
+    month: usize,
+    events: Vec<Event>,
+    errors: Vec<Error>,
+    warnings: Vec<Warning>,
+    fetched_at: Option<DateTime<Utc>>,
+    /// Whether the page came from the mirror of `fallback_url_template` rather than from the dojo's website
+    from_fallback: bool,
+    /// Hash of what the events were parsed from, in incremental mode
+    source_hash: Option<u64>,
+    /// ICS rendered by a previous run from the same source, in incremental mode
+    rendered: Option<String>,
+    /// DTSTAMPs given by the previous run to the events of the month, by UID, in incremental mode
+    dtstamps: BTreeMap<String, state::Stamp>,
+    /// How each line was turned into events, with `explain`
+    explanations: Vec<String>,
+}
+
+impl MonthResult {
+    pub fn new(year: usize, month: usize) -> MonthResult {
+        MonthResult {
+            year,
+            month,
+            events: Vec::new(),
+            errors: Vec::new(),
+            warnings: Vec::new(),
+            fetched_at: None,
+            from_fallback: false,
+            source_hash: None,
+            rendered: None,
+            dtstamps: BTreeMap::new(),
+            explanations: Vec::new(),
+        }
+    }
+
+    /// Add a timed event, returning it unless it was left out as per `on_short_event`
+    fn event(&mut self, day: usize, mut from: Time, mut to: Time, text: &str, location: Option<&str>, config: &Config) -> Option<&mut Event> {
+        // Hours are set in 12 am/pm format, but without the am/pm indication
+        if from.hours < config.afternoon_before {
+            from.hours += 12;
+        }
+        if to.hours < config.afternoon_before {
+            to.hours += 12;
+        }
+        let minutes = (to.hours * 60 + to.minutes).checked_sub(from.hours * 60 + from.minutes);
+        if let (Some(minutes), Some(min)) = (minutes, config.min_interval_mins) {
+            if minutes < min {
+                let text = text.to_owned();
+                if config.on_short_event == ShortEvents::Drop {
+                    self.warning(Warning::ShortEventDropped { day, text, minutes });
+                    return None;
+                }
+                self.warning(Warning::ShortEvent { day, text, minutes });
+            }
+        } else if to <= from {
+            // Only the start was taken as afternoon, like 7:00-9:00 becoming 19:00-9:00, which needs a human to tell
+            self.warning(Warning::EndsBeforeStart {
+                day,
+                text: text.to_owned(),
+                from: format!("{:02}:{:02}", from.hours, from.minutes),
+                to: format!("{:02}:{:02}", to.hours, to.minutes),
+            });
+        }
+        self.events.push(Event {
+            day,
+            time: EventTime::Timed { from, to },
+            text: text.to_owned(),
+            location: location.map(str::to_owned),
+            description: Vec::new(),
+            part: None,
+            attachments: Vec::new(),
+            markup: Vec::new(),
+            uid_override: None,
+            url_override: None,
+        });
+        self.events.last_mut()
+    }
+
+    fn full_day_event(&mut self, day: usize, text: &str, location: Option<&str>) {
+        self.events.push(Event {
+            day,
+            time: EventTime::FullDay,
+            text: text.to_owned(),
+            location: location.map(str::to_owned),
+            description: Vec::new(),
+            part: None,
+            attachments: Vec::new(),
+            markup: Vec::new(),
+            uid_override: None,
+            url_override: None,
+        })
+    }
+
+    fn append_to_last_event(&mut self, text: &str) {
+        self.events.last_mut().unwrap().append(text);
+    }
+
+    fn error(&mut self, err: Error) {
+        self.errors.push(err);
+    }
+
+    fn warning(&mut self, warning: Warning) {
+        self.warnings.push(warning);
+    }
+
+    fn first_day(&self) -> Result<NaiveDate, Error> {
+        let invalid = || Error::InvalidMonth { year: self.year, month: self.month };
+        let year = self.year.try_into().map_err(|_| invalid())?;
+        let month = self.month.try_into().map_err(|_| invalid())?;
+        NaiveDate::from_ymd_opt(year, month, 1).ok_or_else(invalid)
+    }
+
+    fn days_in_month(&self) -> Result<usize, Error> {
+        let first_day = self.first_day()?;
+        let next_month = first_day + Months::new(1);
+        let interval = next_month - first_day;
+        Ok(interval.num_days().try_into().unwrap())
+    }
+
+    fn date_of(&self, event: &Event) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.year.try_into().unwrap(), self.month.try_into().unwrap(), event.day.try_into().unwrap()).unwrap()
+    }
+
+    /// The `extra_events` of the month, leaving out those that the page already has
+    fn extra_events<'a>(&'a self, config: &'a Config) -> impl Iterator<Item = Event> + 'a {
+        let extra = config.extra_events.iter().filter(|e| (e.date.year(), e.date.month()) == (self.year as i32, self.month as u32));
+        let domain = format!("{EXTRA_EVENTS_SUBDOMAIN}.{}", config.uid_domain.trim_start_matches('@'));
+        let events = extra.map(move |e| {
+            let mut event = Event {
+                day: e.date.day() as usize,
+                time: match e.time {
+                    Some(range) => EventTime::Timed {
+                        from: Time { hours: range.from.hour() as usize, minutes: range.from.minute() as usize },
+                        to: Time { hours: range.to.hour() as usize, minutes: range.to.minute() as usize },
+                    },
+                    None => EventTime::FullDay,
+                },
+                text: e.summary.clone(),
+                location: None,
+                description: Vec::new(),
+                part: None,
+                attachments: Vec::new(),
+                markup: Vec::new(),
+                uid_override: None,
+                // The page the events were added to is not theirs
+                url_override: Some(None),
+            };
+            event.uid_override = Some(event.uid(&domain));
+            event
+        });
+        events.filter(|extra| !self.events.iter().any(|e| e.day == extra.day && e.text == extra.text))
+    }
+
+    /// The events to publish, leaving out those outside of `drop_before` and `horizon_days`
+    fn published_events<'a>(&'a self, config: &'a Config) -> impl Iterator<Item = &'a Event> {
+        self.events.iter().filter(|e| is_published(self.date_of(e), config))
+    }
+
+    /// Busy periods of the published events, in UTC
+    fn busy_periods(&self, config: &Config) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        self.published_events(config)
+            .filter_map(|e| {
+                let date = self.date_of(e);
+                match &e.time {
+                    EventTime::Timed { from, to } => Some((to_utc(config.source_tz, date, from), to_utc(config.source_tz, date, to))),
+                    EventTime::FullDay if config.freebusy_full_day => {
+                        let midnight = Time { hours: 0, minutes: 0 };
+                        Some((to_utc(config.source_tz, date, &midnight), to_utc(config.source_tz, date.succ_opt()?, &midnight)))
+                    }
+                    EventTime::FullDay => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn events_as_ics(&self, config: &Config) -> String {
+        if let Some(rendered) = &self.rendered {
+            return rendered.clone();
+        }
+        let mut res = String::new();
+        for e in self.published_events(config) {
+            res.push_str(&self.reuse_dtstamp(e.as_ics(self, config)));
+        }
+        for e in self.extra_events(config).filter(|e| is_published(self.date_of(e), config)) {
+            res.push_str(&self.reuse_dtstamp(e.as_ics(self, config)));
+        }
+        if config.month_summary_event {
+            // Only timed events are sessions, all-day ones being closures, holidays and the like
+            let sessions = self.published_events(config).filter(|e| matches!(e.time, EventTime::Timed { .. })).count();
+            let domain = config.uid_domain.trim_start_matches('@');
+            let summary = Event {
+                day: 1,
+                time: EventTime::FullDay,
+                text: config.locale.month_summary(sessions),
+                location: None,
+                description: Vec::new(),
+                part: None,
+                attachments: Vec::new(),
+                markup: Vec::new(),
+                // The UID would otherwise change with the count, and collide between months with the same one
+                uid_override: Some(format!("{MONTH_SUMMARY_UID}{:04}{:02}@{domain}", self.year, self.month)),
+                url_override: None,
+            };
+            res.push_str(&summary.as_ics(self, config));
+        }
+        if config.include_errors_in_calendar && !self.errors.is_empty() {
+            let errors = Event {
+                day: 1,
+                time: EventTime::FullDay,
+                text: format!("shinbukan-ics: {} errors while processing this month", self.errors.len()),
+                location: None,
+                description: self.errors.iter().map(|e| e.to_string()).collect(),
+                part: None,
+                attachments: Vec::new(),
+                markup: Vec::new(),
+                uid_override: None,
+                url_override: None,
+            };
+            res.push_str(&errors.as_ics(self, config));
+        }
+        res
+    }
+
+    /// The events left out by `drop_before` and `horizon_days`, for `rdate_repeats` to find their repeats among the others
+    ///
+    /// Months reused from the previous run only have their published events, and give none.
+    fn unpublished_events_as_ics(&self, config: &Config) -> String {
+        self.events.iter().filter(|e| !is_published(self.date_of(e), config)).map(|e| e.as_ics(self, config)).collect()
+    }
+
+    /// Give the rendered event the DTSTAMP of the previous run back if it did not change since then, so that clients
+    /// do not take it as modified
+    fn reuse_dtstamp(&self, ics: String) -> String {
+        let lines = ics.lines().collect::<Vec<_>>();
+        let previous = lines.iter().find_map(|l| l.strip_prefix("UID:")).and_then(|uid| self.dtstamps.get(uid));
+        let Some(previous) = previous.filter(|p| p.hash == state::event_hash(&lines)) else {
+            return ics;
+        };
+        let stamp = format!("DTSTAMP:{}", previous.dtstamp);
+        lines.iter().map(|l| if l.starts_with("DTSTAMP:") { &stamp } else { *l }).map(|l| format!("{l}\n")).collect()
+    }
+
+    pub fn year(&self) -> usize {
+        self.year
+    }
+
+    pub fn month(&self) -> usize {
+        self.month
+    }
+
+    /// Number of events of the month, whether parsed in this run or reused from the previous one
+    pub fn event_count(&self) -> usize {
+        match &self.rendered {
+            Some(rendered) => {
+                let added = rendered.lines().filter_map(|l| l.strip_prefix("UID:")).filter(|uid| {
+                    uid.starts_with(MONTH_SUMMARY_UID) || uid.split_once('@').is_some_and(|(_, domain)| domain.starts_with(&format!("{EXTRA_EVENTS_SUBDOMAIN}.")))
+                });
+                let added = added.count();
+                rendered.lines().filter(|l| *l == "BEGIN:VEVENT").count() - added
+            }
+            None => self.events.len(),
+        }
+    }
+
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Whether the events may be out of date, as reused from the previous run or served by the fallback mirror
+    pub fn served_stale(&self) -> bool {
+        self.rendered.is_some() || self.from_fallback
+    }
+
+    /// How each line of the page was turned into events, one multi-line entry per line, if `explain` is set
+    pub fn explanations(&self) -> &[String] {
+        &self.explanations
+    }
+}
+
+static TABLES: LazyLock<scraper::Selector> = LazyLock::new(|| scraper::Selector::parse("table[summary]").unwrap());
+static ALL_TABLES: LazyLock<scraper::Selector> = LazyLock::new(|| scraper::Selector::parse("table").unwrap());
+static TITLE: LazyLock<scraper::Selector> = LazyLock::new(|| scraper::Selector::parse("title").unwrap());
+static CELLS: LazyLock<scraper::Selector> = LazyLock::new(|| scraper::Selector::parse("td").unwrap());
+
+pub fn parse_calendar(res: &mut MonthResult, cal: &str, config: &Config) {
+    // The host serves its login page instead of the calendar once the session expired, still with a 200
+    if let Some(marker) = config.auth_failure_markers.iter().find(|m| !m.is_empty() && cal.contains(m.as_str())) {
+        res.error(Error::AuthFailure { marker: marker.clone() });
+        return;
+    }
+    let doc = scraper::Html::parse_document(cal);
+    let (first_day, days_in_month) = match (res.first_day(), res.days_in_month()) {
+        (Ok(first_day), Ok(days_in_month)) => (first_day, days_in_month),
+        (Err(err), _) | (_, Err(err)) => {
+            res.error(err);
+            return;
+        }
+    };
+    let mut tables = schedule_tables(&doc, config).collect::<Vec<_>>();
+    if !tables.iter().any(|table| own_cells(*table).next().is_some()) {
+        match fallback_table(&doc, days_in_month) {
+            Some(table) => {
+                res.warning(Warning::FallbackTable { summary: table.value().attr("summary").unwrap_or_default().to_owned() });
+                tables = vec![table];
+            }
+            // Pages served instead of the calendar, like login or error pages, would otherwise report every day as
+            // missing
+            None => {
+                let title = doc.select(&TITLE).next().map(|t| clean_text(&t.text().collect::<String>()).trim().to_owned());
+                res.error(Error::MissingTable { title: title.unwrap_or_default() });
+                return;
+            }
+        }
+    }
+    // Whether each day was seen in any of the tables, which may each hold part of the schedule (e.g. mornings and
+    // evenings) and thus all list the same days
+    let mut parsed_days = vec![false; days_in_month];
+    let mut blank_days = vec![false; days_in_month];
+    // The grid starts on Sundays, so day N is in the cell at index `first_cell + N - 1`
+    let first_cell = first_day.weekday().num_days_from_sunday() as usize;
+    for (t, table) in tables.into_iter().enumerate() {
+        let mut parsed_in_table = vec![false; days_in_month];
+        for (i, element) in own_cells(table).enumerate() {
+            let day = find_day_number(&element).map(|(day, _)| day);
+            if !config.only_days.is_empty() && !day.is_some_and(|day| config.only_days.contains(&day)) {
+                continue;
+            }
+            let position = CellPosition { table: t + 1, cell: i + 1 };
+            // Tables found by `fallback_table` may hold other numbers, and typos may give days the month does not have
+            if let Some(day) = day.filter(|day| !(1..=days_in_month).contains(day)) {
+                res.error(Error::DayOutOfRange { day, days_in_month, position });
+                continue;
+            }
+            match parse_cell(&mut *res, &element, position, config) {
+                Some(day) if !parsed_in_table[day - 1] => parsed_in_table[day - 1] = true,
+                Some(day) => res.error(Error::DuplicateDay { day }),
+                None => {
+                    // Months without a schedule yet are a grid of blank cells, without even the day numbers
+                    let day = (i + 1).checked_sub(first_cell).filter(|d| (1..=days_in_month).contains(d));
+                    if let Some(day) = day {
+                        if element.text().all(|t| t.trim().is_empty()) {
+                            blank_days[day - 1] = true;
+                        }
+                    }
+                }
+            }
+        }
+        for (parsed, in_table) in parsed_days.iter_mut().zip(parsed_in_table) {
+            *parsed |= in_table;
+        }
+    }
+    if !parsed_days.iter().any(|p| *p) && blank_days.iter().all(|b| *b) {
+        res.warning(Warning::BlankMonth);
+    }
+    for (day, (parsed, blank)) in parsed_days.iter().zip(&blank_days).enumerate() {
+        let wanted = config.only_days.is_empty() || config.only_days.contains(&(day + 1));
+        if !parsed && !blank && wanted {
+            res.error(Error::MissingDay { day: day + 1 });
+        }
+    }
+    if config.merge_adjacent {
+        merge_adjacent(&mut res.events);
+    }
+    if config.merge_fullday {
+        merge_fullday(&mut res.events);
+    }
+    if config.check_overlaps {
+        for warning in overlaps(&res.events) {
+            res.warning(warning);
+        }
+    }
+    // Report problems in calendar order rather than in the order the HTML happened to be traversed
+    res.errors.sort_by_key(Error::sort_key);
+    res.warnings.sort_by_key(Warning::day);
+}
+
+/// Merge timed events that directly follow one another on the same day and with the same description
+fn merge_adjacent(events: &mut Vec<Event>) {
+    let mut merged: Vec<Event> = Vec::with_capacity(events.len());
+    for event in events.drain(..) {
+        if let Some(last) = merged.last_mut() {
+            if let (EventTime::Timed { to, .. }, EventTime::Timed { from: next_from, to: next_to }) = (&mut last.time, &event.time) {
+                let same = last.day == event.day
+                    && last.text == event.text
+                    && last.location == event.location
+                    && last.description == event.description
+                    && last.attachments == event.attachments
+                    && last.markup == event.markup;
+                if same && to == next_from {
+                    *to = *next_to;
+                    continue;
+                }
+            }
+        }
+        merged.push(event);
+    }
+    *events = merged;
+}
+
+/// Combine the all-day events of each day into the first one, listing them all in its description
+fn merge_fullday(events: &mut Vec<Event>) {
+    let mut merged: Vec<Event> = Vec::with_capacity(events.len());
+    // Position of the first all-day event of each day, along with the texts combined into it
+    let mut combined: BTreeMap<usize, (usize, Vec<String>)> = BTreeMap::new();
+    for event in events.drain(..) {
+        if !matches!(event.time, EventTime::FullDay) {
+            merged.push(event);
+            continue;
+        }
+        let Some((i, texts)) = combined.get_mut(&event.day) else {
+            combined.insert(event.day, (merged.len(), vec![event.text.clone()]));
+            merged.push(event);
+            continue;
+        };
+        let first = &mut merged[*i];
+        texts.push(event.text);
+        first.description.extend(event.description);
+        if first.location != event.location {
+            first.location = None;
+        }
+        first.attachments.extend(event.attachments);
+        first.markup.extend(event.markup);
+    }
+    for (i, texts) in combined.into_values().filter(|(_, texts)| texts.len() > 1) {
+        merged[i].text = texts.join(" / ");
+        merged[i].description.splice(0..0, texts);
+    }
+    *events = merged;
+}
+
+/// The tables holding the schedule, as told apart from the layout ones by their `summary` attribute
+fn schedule_tables<'a>(doc: &'a scraper::Html, config: &'a Config) -> impl Iterator<Item = scraper::ElementRef<'a>> {
+    doc.select(&TABLES).filter(|table| {
+        let summary = table.value().attr("summary").unwrap_or_default();
+        config.schedule_summaries.iter().any(|s| s == summary)
+    })
+}
+
+/// The table holding the most day numbers, for pages where no table has the expected summary, if it holds enough of
+/// them to plausibly be the schedule
+fn fallback_table(doc: &scraper::Html, days_in_month: usize) -> Option<scraper::ElementRef<'_>> {
+    let (days, table) = doc
+        .select(&ALL_TABLES)
+        .map(|table| {
+            let days = own_cells(table).filter_map(|c| find_day_number(&c)).map(|(day, _)| day);
+            let days = days.filter(|day| (1..=days_in_month).contains(day)).collect::<std::collections::BTreeSet<_>>();
+            (days.len(), table)
+        })
+        .max_by_key(|(days, _)| *days)?;
+    (days * 2 >= days_in_month).then_some(table)
+}
+
+/// The cells of the table, leaving out those of the tables nested in it
+fn own_cells(table: scraper::ElementRef<'_>) -> impl Iterator<Item = scraper::ElementRef<'_>> {
+    table.select(&CELLS).filter(move |cell| {
+        let parent_table = cell.ancestors().find(|a| matches!(a.value(), Node::Element(e) if e.name() == "table"));
+        parent_table.is_some_and(|t| t.id() == table.id())
+    })
+}
+
+/// Warn about timed events at the same place that overlap, which usually means a typo in the schedule
+fn overlaps(events: &[Event]) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for (i, first) in events.iter().enumerate() {
+        for second in &events[i + 1..] {
+            if let (EventTime::Timed { from, to }, EventTime::Timed { from: other_from, to: other_to }) = (&first.time, &second.time) {
+                if first.day == second.day && first.location == second.location && from < other_to && other_from < to {
+                    warnings.push(Warning::Overlap { day: first.day, first: first.text.clone(), second: second.text.clone() });
+                }
+            }
+        }
+    }
+    warnings
+}
+
+/// Find the day number leading a cell, returning it along with the child node that holds it
+fn find_day_number<'a>(cell: &scraper::ElementRef<'a>) -> Option<(usize, ego_tree::NodeRef<'a, Node>)> {
+    let first = cell.children().find(|c| !is_whitespace(c.value()))?;
+    Some((get_day_number(first)?, first))
+}
+
+fn get_day_number(node: ego_tree::NodeRef<'_, Node>) -> Option<usize> {
+    match node.value() {
+        Node::Text(txt) => parse_day_number(clean_text(txt).trim()),
+        // The number is sometimes emphasized, e.g. for holidays
+        Node::Element(elt) if matches!(elt.name(), "b" | "strong" | "font" | "span") => {
+            get_day_number(node.children().find(|c| !is_whitespace(c.value()))?)
+        }
+        _ => None,
+    }
+}
+
+/// Parse a day number, that some templates follow with 日 or the weekday, as in `15日`, `15（金）` or `15日(金)`
+fn parse_day_number(txt: &str) -> Option<usize> {
+    let (day, rest) = txt.split_at(txt.find(|c: char| !c.is_ascii_digit()).unwrap_or(txt.len()));
+    let rest = rest.strip_prefix('日').unwrap_or(rest).trim_start();
+    let weekday = rest.strip_prefix(['(', '（']).and_then(|r| r.strip_suffix([')', '）']));
+    let valid = match weekday {
+        Some(weekday) => (0..7).any(|d| Locale::Ja.weekday(chrono::Weekday::try_from(d).unwrap()) == weekday.trim()),
+        None => rest.is_empty(),
+    };
+    valid.then(|| day.parse().ok()).flatten().filter(|day| (1..=31).contains(day))
+}
+
+fn is_whitespace(node: &Node) -> bool {
+    matches!(node, Node::Text(txt) if clean_text(txt).trim().is_empty())
+}
+
+/// Whether the link points to a PDF document, ignoring any query or fragment
+fn is_pdf(href: &str) -> bool {
+    let path = href.split(['?', '#']).next().unwrap_or_default();
+    path.to_ascii_lowercase().ends_with(".pdf")
+}
+
+/// Remove the zero-width characters and byte order marks that hand-edited pages sometimes hide in their text
+fn clean_text(txt: &str) -> String {
+    txt.chars().filter(|c| !matches!(c, '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}' | '\u{feff}')).collect()
+}
+
+/// Parse a time, where times past midnight are written on from 24:00, as in `22:00-25:00`, until the end of the next day
+fn parse_time(time: &str) -> Option<Time> {
+    let (hours, minutes) = time.split_once(':').unwrap_or((time, "0"));
+    let time = Time { hours: hours.parse().ok()?, minutes: minutes.parse().ok()? };
+    (time.hours < 48 && time.minutes < 60).then_some(time)
+}
+
+/// Convert a local time of the calendar to UTC, rolling hours past 23 over to the next day
+fn to_utc(tz: chrono_tz::Tz, date: NaiveDate, time: &Time) -> DateTime<Utc> {
+    let minutes = (time.hours * 60 + time.minutes).try_into().unwrap();
+    let local = date.and_time(chrono::NaiveTime::MIN) + chrono::Duration::minutes(minutes);
+    match tz.from_local_datetime(&local).earliest() {
+        Some(t) => t.with_timezone(&Utc),
+        // The time was skipped by a DST change, and is most likely meant in the offset from before the change
+        None => tz.from_local_datetime(&(local + chrono::Duration::hours(1))).earliest().unwrap().with_timezone(&Utc),
+    }
+}
+
+/// Whether events on this day are published, as per `drop_before` and `horizon_days`
+fn is_published(date: NaiveDate, config: &Config) -> bool {
+    let before = config.drop_before.is_some_and(|anchor| date < anchor.resolve(config.today()));
+    let after = config.horizon_days.is_some_and(|days| date > config.today() + chrono::Duration::days(days.into()));
+    !before && !after
+}
+
+/// The DTSTAMP of generated components
+fn dtstamp(config: &Config) -> String {
+    // Pinning the stamp to the day of the run keeps the output identical across runs of the same day
+    let now = if config.pin_dtstamp {
+        config.today().and_hms_opt(0, 0, 0).unwrap().and_utc()
+    } else if cfg!(test) {
+        DateTime::UNIX_EPOCH.with_year(2000).unwrap()
+    } else {
+        config.now
+    };
+    now.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escape a value of type TEXT, as per RFC 5545 section 3.3.11
+fn escape_text(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                res.push('\\');
+                res.push(c);
+            }
+            '\n' => res.push_str("\\n"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Resolve a link of the page at `url`, as they are usually relative to it
+fn resolve_link(url: &str, href: &str) -> String {
+    reqwest::Url::parse(url).and_then(|base| base.join(href)).map_or(href.to_owned(), String::from)
+}
+
+fn looks_like_time(time: &str) -> bool {
+    time.starts_with(|c: char| c.is_ascii_digit()) && time.chars().all(|c| c.is_ascii_digit() || c == ':')
+}
+
+/// Parse one line of a day cell into an event
+fn parse_text(res: &mut MonthResult, day: usize, txt: &str, config: &Config) {
+    let first = res.events.len();
+    // How the line was read, for `explain`
+    let mut notes = Vec::new();
+    classify_text(res, day, txt, config, &mut notes);
+    if !config.explain {
+        return;
+    }
+    for event in &res.events[first..] {
+        notes.push(match &event.time {
+            EventTime::Timed { from, to } => format!("event at {}-{}: {:?}", from, to, event.text),
+            EventTime::FullDay => format!("all-day event: {:?}", event.text),
+        });
+    }
+    let date = format!("{:04}-{:02}-{day:02}", res.year, res.month);
+    res.explanations.push(format!("{date} {txt:?}\n{}", notes.iter().map(|n| format!("  {n}\n")).collect::<String>()));
+}
+
+/// Read a line of a cell into events, noting the decisions made along the way
+fn classify_text(res: &mut MonthResult, day: usize, txt: &str, config: &Config, notes: &mut Vec<String>) {
+    let txt = match split_date(txt) {
+        Some((month, written_day, rest)) => {
+            let date = txt[..txt.len() - rest.len()].trim();
+            notes.push(format!("leading date {date:?} removed"));
+            if (month, written_day) != (res.month, day) {
+                res.warning(Warning::MismatchedDate { day, date: date.to_owned() });
+            }
+            rest
+        }
+        None => txt,
+    };
+    let (location, txt) = split_venue(txt, config);
+    if let Some(location) = location {
+        notes.push(format!("venue {location:?} moved to the location"));
+    }
+    // An explicit marker such as `終日` makes the whole line an all-day event, whatever digits it contains
+    if let Some(keyword) = config.all_day_keywords.iter().find(|keyword| txt.contains(keyword.as_str())) {
+        notes.push(format!("all day, as it contains {keyword:?}"));
+        return res.full_day_event(day, txt, location);
+    }
+    // The time can be followed by several spaces, including ideographic ones
+    let (raw_time, rem) = txt.split_once(char::is_whitespace).map_or((txt, ""), |(time, rem)| (time, rem.trim_start()));
+    // Time ranges are sometimes bulleted or bracketed, as in `・19:00-21:00` or `(19:00-21:00)`
+    let time = raw_time.trim_matches(|c: char| c.is_whitespace() || config.decorations.contains(c));
+    if time != raw_time {
+        notes.push(format!("decorations stripped from {raw_time:?}"));
+    }
+    // Several ranges can share a summary, e.g. `10:00-12:00,13:00-17:00` around a lunch break
+    let ranges = time.split([',', '，']).map(parse_range).collect::<Option<Vec<_>>>();
+    // Lines that start like a time range but do not hold a valid one are kept, so that nothing is lost to typos
+    let ranges = match ranges.map(|ranges| ranges.into_iter().collect::<Option<Vec<_>>>()) {
+        Some(None) => {
+            res.error(Error::InvalidTimeRange { day, range: time.to_owned() });
+            notes.push(format!("all day, as {time:?} is not a valid time range"));
+            return res.full_day_event(day, txt, location);
+        }
+        ranges => ranges.flatten(),
+    };
+    match ranges {
+        Some(ranges) => {
+            let mut separators = time.chars().filter(|c| RANGE_SEPARATORS.contains(c)).map(|c| format!("{c:?}")).collect::<Vec<_>>();
+            separators.dedup();
+            notes.push(match ranges.len() {
+                1 => format!("time range {time:?}, split on {}", separators.join(" and ")),
+                n => format!("{n} time ranges in {time:?}, split on {}", separators.join(" and ")),
+            });
+            let summary = match (rem, &config.empty_summary) {
+                ("", Some(default)) => {
+                    notes.push(format!("no summary, {default:?} used instead"));
+                    default
+                }
+                ("", None) => {
+                    res.warning(Warning::EmptySummary { day });
+                    ""
+                }
+                (rem, _) => rem,
+            };
+            // Groups sharing a session each get an event, as in `少年部/一般部`
+            let mut summaries = summary.split(|c| config.split_summary_on.contains(c)).map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<_>>();
+            if summaries.is_empty() {
+                summaries.push(summary);
+            }
+            if summaries.len() > 1 {
+                notes.push(format!("summary split into {} groups on {:?}", summaries.len(), config.split_summary_on));
+            }
+            let split = ranges.len() * summaries.len() > 1;
+            let parts = ranges.iter().flat_map(|range| summaries.iter().map(move |summary| (range, summary)));
+            for (i, ((from, to), summary)) in parts.enumerate() {
+                let Some(event) = res.event(day, *from, *to, summary, location, config) else {
+                    continue;
+                };
+                if let EventTime::Timed { from: read_from, to: read_to } = &event.time {
+                    for (written, read) in [(from, read_from), (to, read_to)].into_iter().filter(|(written, read)| written != read) {
+                        let note = format!("{written} read as {read}, as hours before {} are in the afternoon", config.afternoon_before);
+                        // Ranges shared by several groups would repeat the notes about them
+                        if !notes.contains(&note) {
+                            notes.push(note);
+                        }
+                    }
+                }
+                if split {
+                    event.part = Some(i + 1);
+                }
+            }
+        }
+        None => {
+            notes.push(format!("all day, as {time:?} is not a time range"));
+            res.full_day_event(day, txt, location)
+        }
+    }
+}
+
+/// What separates the start and end of time ranges, where dashes pasted from word processors are used as well
+const RANGE_SEPARATORS: [char; 4] = ['-', '~', '–', '—'];
+
+/// Parse a time range, returning `None` for text that is no time range and `Some(None)` for invalid ones like `19:-21`
+fn parse_range(range: &str) -> Option<Option<(Time, Time)>> {
+    let (from, to) = range.split_once(RANGE_SEPARATORS)?;
+    if !(looks_like_time(from) && looks_like_time(to)) {
+        return None;
+    }
+    Some(parse_time(from).and_then(|start| Some((start, parse_end(from, start, to)?))))
+}
+
+/// Parse the end of a time range, which may only give the minutes within the hour of the start, as in `19:00-30`
+///
+/// After a start with minutes, a colon-less end of two digits is taken as minutes when it cannot be the hour the range
+/// ends at, being past 23 or before the hour of the start, and then rolls to the next hour if it is not after the
+/// start. Single digits are always hours: `19:00-5` ends at 5, that is 17:00, and is reported as ending before it
+/// starts rather than silently becoming a 5-minute session.
+fn parse_end(from: &str, start: Time, to: &str) -> Option<Time> {
+    let minutes = to.parse::<usize>().ok().filter(|m| from.contains(':') && to.len() == 2 && (*m >= 24 || *m < start.hours));
+    match minutes {
+        Some(minutes) => {
+            let minutes = if minutes > start.minutes { minutes } else { minutes + 60 };
+            Some(Time { hours: start.hours + minutes / 60, minutes: minutes % 60 })
+        }
+        None => parse_time(to),
+    }
+}
+
+/// Split a leading date, written `M/D` or `M月D日`, off the text, returning the month, the day and the rest
+fn split_date(txt: &str) -> Option<(usize, usize, &str)> {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let month_len = digits(txt);
+    let (month, rest) = txt.split_at(month_len);
+    let (rest, japanese) = match rest.strip_prefix('/') {
+        Some(rest) => (rest, false),
+        None => (rest.strip_prefix('月')?, true),
+    };
+    let (day, mut rest) = rest.split_at(digits(rest));
+    if japanese {
+        rest = rest.strip_prefix('日')?;
+    } else if !(rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        return None;
+    }
+    Some((month.parse().ok()?, day.parse().ok()?, rest.trim_start()))
+}
+
+/// Split a leading configured venue off the text, returning the venue and the rest
+fn split_venue<'a>(txt: &'a str, config: &'a Config) -> (Option<&'a str>, &'a str) {
+    for venue in &config.venues {
+        if let Some(rest) = txt.strip_prefix(venue.as_str()) {
+            if rest.starts_with(char::is_whitespace) {
+                return (Some(venue), rest.trim_start());
+            }
+        }
+    }
+    (None, txt)
+}
+
+// Returns the number of the parsed day, if applicable
+fn parse_cell(res: &mut MonthResult, cell: &scraper::ElementRef<'_>, position: CellPosition, config: &Config) -> Option<usize> {
+    let (day_num, day_num_node) = find_day_number(cell)?;
+    parse_lines(res, day_num, day_num_node.next_siblings(), cell, position, config);
+    Some(day_num)
+}
+
+/// Parse the nodes of a cell that follow its day number, where each line holds an event
+fn parse_lines<'a>(
+    res: &mut MonthResult,
+    day_num: usize,
+    nodes: impl Iterator<Item = ego_tree::NodeRef<'a, Node>>,
+    cell: &scraper::ElementRef<'_>,
+    position: CellPosition,
+    config: &Config,
+) {
+    // Whether the last event's line goes on, i.e. no line break was met since its text
+    let mut in_line = false;
+    for c in nodes {
+        match c.value() {
+            Node::Element(elt) => match elt.name() {
+                "br" => in_line = false,
+                // Layout tables are sometimes nested in the cells, with each of their own cells holding lines
+                "table" => {
+                    for inner in own_cells(scraper::ElementRef::wrap(c).unwrap()) {
+                        parse_lines(res, day_num, inner.children(), cell, position, config);
+                    }
+                    in_line = false;
+                }
+                // Red notes matter whatever their size, like a small 中止 after the event it cancels
+                "font" if elt.attr("color") == Some("red") => {
+                    for n in c.descendants() {
+                        if let Node::Text(txt) = n.value() {
+                            let txt = clean_text(txt);
+                            if txt.trim().is_empty() {
+                                continue;
+                            }
+                            // Notes before any event of the day are events of their own
+                            match res.events.last_mut().filter(|event| event.day == day_num) {
+                                Some(event) => event.append(&txt),
+                                None => {
+                                    res.full_day_event(day_num, txt.trim(), None);
+                                    in_line = true;
+                                }
+                            }
+                            res.events.last_mut().unwrap().markup.push(Markup::Emphasis(txt.trim().to_owned()));
+                        }
+                    }
+                }
+                "font" if elt.attr("size") == Some("-1") => continue,
+                "a" => {
+                    let txt = clean_text(&c.descendants().filter_map(|n| n.value().as_text().map(|t| &**t)).collect::<String>());
+                    let txt = txt.trim();
+                    // A link either is a line of its own, or labels the event it follows
+                    let href = elt.attr("href");
+                    let linked = match in_line {
+                        true => {
+                            if !txt.is_empty() {
+                                res.append_to_last_event(txt);
+                                if let Some(href) = href {
+                                    res.events.last_mut().unwrap().markup.push(Markup::Link { text: txt.to_owned(), href: href.to_owned() });
+                                }
+                            }
+                            res.events.len().saturating_sub(1)
+                        }
+                        false => {
+                            let linked = res.events.len();
+                            if !txt.is_empty() {
+                                parse_text(res, day_num, txt, config);
+                                in_line = true;
+                            }
+                            // The whole text of the events of the line is linked, past the times it started with
+                            if let Some(href) = href {
+                                for event in &mut res.events[linked..] {
+                                    event.markup.push(Markup::Link { text: event.text.clone(), href: href.to_owned() });
+                                }
+                            }
+                            linked
+                        }
+                    };
+                    if let Some(href) = href.filter(|href| is_pdf(href)) {
+                        for event in &mut res.events[linked..] {
+                            event.attachments.push(href.to_owned());
+                        }
+                    }
+                }
+                _ => res.error(Error::unexpected_element(day_num, elt, position, cell)),
+            }
+            Node::Text(txt) => {
+                let txt = clean_text(txt);
+                let txt = txt.trim();
+                if txt.is_empty() {
+                    continue;
+                }
+                parse_text(res, day_num, txt, config);
+                in_line = true;
+            }
+            _ => res.error(Error::UnexpectedNode { day: day_num, position, node: format!("{:?}", c.value()) }),
+        }
+    }
+}
+
+/// Parse a captured page named after its month, like `2024-06.html` or `2024-06-description.html`
+///
+/// A TOML configuration next to the page, with the same name, replaces `config` for this page, as for the fixtures.
+pub fn parse_file(path: &std::path::Path, config: &Config) -> anyhow::Result<MonthResult> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let mut yearmonth = name.split('.').next().unwrap_or_default().split('-');
+    let (Some(Ok(year)), Some(Ok(month @ 1..=12))) = (yearmonth.next().map(str::parse), yearmonth.next().map(str::parse)) else {
+        anyhow::bail!("{path:?} is not named after its month, as in 2024-06.html");
+    };
+    let config_path = path.with_extension("toml");
+    let own_config = match config_path.exists() {
+        true => Some(Config::from_file(&config_path)?),
+        false => None,
+    };
+    let page = decode_page(&std::fs::read(path).with_context(|| format!("Reading calendar page {path:?}"))?);
+    let mut result = MonthResult::new(year, month);
+    match own_config {
+        // Debugging settings still apply to pages with their own configuration
+        Some(own) => parse_calendar(&mut result, &page, &Config { only_days: config.only_days.clone(), ..own }),
+        None => parse_calendar(&mut result, &page, config),
+    }
+    Ok(result)
+}
+
+pub async fn handle_month(fetcher: &impl CalendarFetcher, year: usize, month: usize, config: &Config) -> MonthResult {
+    handle_month_incremental(fetcher, year, month, config, None).await
+}
+
+/// Like `handle_month`, but reusing what the previous run rendered if the page did not change since then
+pub async fn handle_month_incremental(
+    fetcher: &impl CalendarFetcher,
+    year: usize,
+    month: usize,
+    config: &Config,
+    state: Option<&State>,
+) -> MonthResult {
+    let mut result = MonthResult::new(year, month);
+    let cal = match fetcher.fetch(year, month).await {
+        Ok(cal) => {
+            result.fetched_at = Some(Utc::now());
+            result.from_fallback = fetcher.served_by_fallback(year, month);
+            cal
+        }
+        Err(err) => {
+            result.error(Error::Fetch(err));
+            return result;
+        }
+    };
+    // Bytes that are not valid EUC-JP are decoded as replacement characters, which would end up in the events
+    if let Some((count, line, context)) = error::decoding_errors(&cal).filter(|_| !config.tolerate_encoding_errors) {
+        match config.strict {
+            true => result.error(Error::Encoding { line, count, context }),
+            false => result.warning(Warning::Encoding { line, count, context }),
+        }
+    }
+    // Previous renderings are only kept as ICS events, so other formats need to parse every month
+    if let Some(state) = state.filter(|_| config.format == OutputFormat::Ics) {
+        let source_hash = state::source_hash(&cal, config);
+        result.source_hash = Some(source_hash);
+        // Explaining the events needs going through the parsing again
+        if let Some(rendered) = state.rendered(year, month, source_hash).filter(|_| !config.explain) {
+            tracing::debug!("{year:04}-{month:02} is unchanged since the previous run");
+            result.rendered = Some(rendered.to_owned());
+            return result;
+        }
+        result.dtstamps = state.dtstamps(year, month);
+    }
+    parse_calendar(&mut result, &cal, config);
+    result
+}
+
+/// Fetch and parse the months, `concurrency` of them at a time, returning their results in chronological order
+///
+/// Months are handled in whatever order their pages come, so that a slow one does not hold up the others. With
+/// `auth_fail_fast`, the months still to come are given up on once one fails to authenticate.
+pub async fn handle_months(fetcher: &impl CalendarFetcher, months: &[YearMonth], config: &Config, state: Option<&State>) -> Vec<MonthResult> {
+    let mut pending = futures::stream::iter(months)
+        .map(|m| handle_month_incremental(fetcher, m.year, m.month, config, state))
+        .buffer_unordered(config.concurrency.max(1));
+    let mut results = Vec::with_capacity(months.len());
+    while let Some(result) = pending.next().await {
+        let auth_failed = result.errors.iter().any(|e| matches!(e, Error::AuthFailure { .. }));
+        results.push(result);
+        if auth_failed && config.auth_fail_fast {
+            break;
+        }
+    }
+    results.sort_by_key(|r| (r.year, r.month));
+    results
+}
+
+/// Check that the run found enough events, to catch upstream changes that the parser misreads without erroring
+pub fn check_event_count(results: &[MonthResult], config: &Config, previous: Option<&State>) -> Option<Error> {
+    let count = results.iter().map(MonthResult::event_count).sum();
+    let mut expected = config.min_events;
+    if let (Some(percent), Some(previous)) = (config.min_events_vs_previous, previous.and_then(State::total_events)) {
+        expected = expected.max((previous * percent).div_ceil(100));
+    }
+    (count < expected).then_some(Error::TooFewEvents { count, expected })
+}
+
+/// The months whose calendar should be fetched, in chronological order
+///
+/// Fails if any of them is outside of the plausible years, which would mean that `now` or `months` has a typo.
+pub fn months_to_fetch(config: &Config) -> anyhow::Result<Vec<YearMonth>> {
+    let months = if !config.months.is_empty() {
+        let mut months = config.months.clone();
+        months.sort();
+        months.dedup();
+        months
+    } else {
+        let first_date = config.today() - Months::new(config.months_back);
+        (0..=config.months_back + config.months_ahead)
+            .map(|add_months| {
+                let for_date = first_date + Months::new(add_months);
+                YearMonth {
+                    // Years before 0 are caught below, as being before `min_year`
+                    year: for_date.year().try_into().unwrap_or(0),
+                    month: for_date.month().try_into().unwrap(),
+                }
+            })
+            .collect()
+    };
+    let plausible = config.min_year..=config.max_year;
+    if let Some(m) = months.iter().find(|m| !plausible.contains(&m.year)) {
+        anyhow::bail!(
+            "Refusing to fetch {}, as it is outside of the plausible years {}-{}",
+            String::from(*m),
+            config.min_year,
+            config.max_year,
+        );
+    }
+    Ok(months)
+}
+
+/// Assemble the full calendar out of the per-month results, in the configured format
+pub fn render_calendar(results: &[MonthResult], config: &Config) -> String {
+    let body = match config.format {
+        OutputFormat::Ics => {
+            let events = sort_events(&results.iter().map(|r| r.events_as_ics(config)).collect::<String>(), config);
+            let events = match config.rdate_repeats {
+                true => collapse_repeats(&events, &results.iter().map(|r| r.unpublished_events_as_ics(config)).collect::<String>()),
+                false => events,
+            };
+            let events = match config.on_uid_collision {
+                UidCollision::Suffix => suffix_uid_collisions(&events),
+                UidCollision::Error | UidCollision::Ignore => events,
+            };
+            let midnight = Time { hours: 0, minutes: 0 };
+            let first = results.iter().filter_map(|r| r.first_day().ok()).min();
+            let last = results.iter().filter_map(|r| r.first_day().ok()).max();
+            match (config.display_tz, first, last) {
+                (Some(tz), Some(first), Some(last)) => {
+                    let (from, to) = (to_utc(config.source_tz, first, &midnight), to_utc(config.source_tz, last + Months::new(1), &midnight));
+                    format!("{}{events}", timezone::vtimezone(tz, from, to))
+                }
+                _ => events,
+            }
+        }
+        OutputFormat::Freebusy => render_freebusy(results, config),
+        OutputFormat::Jcal => return jcal::from_ics(&render_calendar(results, &Config { format: OutputFormat::Ics, ..config.clone() })),
+    };
+    wrap_calendar(&body, config)
+}
+
+/// The UIDs given to several events, along with the number of these events
+///
+/// UIDs are hashes of the contents of the events, which leave the month out so that they stay stable: that makes the
+/// same events on the same day of different months collide, as well as repeated lines.
+pub fn uid_collisions(results: &[MonthResult], config: &Config) -> Vec<(String, usize)> {
+    let events = results.iter().map(|r| r.events_as_ics(config)).collect::<String>();
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for uid in events.lines().filter_map(|l| l.strip_prefix("UID:")) {
+        *counts.entry(uid).or_default() += 1;
+    }
+    counts.into_iter().filter(|(_, count)| *count > 1).map(|(uid, count)| (uid.to_owned(), count)).collect()
+}
+
+/// Order the rendered events as per `sort_by`, keeping the order of the page for those that compare equal
+///
+/// This works on the rendered events, as those of the months that did not change come as such in incremental mode.
+fn sort_events(events: &str, config: &Config) -> String {
+    let property = |component: &[&str], name: &str| {
+        let line = component.iter().find(|l| l.strip_prefix(name).is_some_and(|rest| rest.starts_with([':', ';'])))?;
+        Some(line.split_once(':')?.1.to_owned())
+    };
+    let start = |component: &[&str]| {
+        let start = property(component, "DTSTART")?;
+        if let Ok(start) = NaiveDateTime::parse_from_str(&start, "%Y%m%dT%H%M%SZ") {
+            return Some(start.and_utc());
+        }
+        if let (Ok(start), Some(tz)) = (NaiveDateTime::parse_from_str(&start, "%Y%m%dT%H%M%S"), config.display_tz) {
+            return Some(tz.from_local_datetime(&start).earliest()?.with_timezone(&Utc));
+        }
+        // All-day events start at midnight where the dojo is
+        Some(to_utc(config.source_tz, NaiveDate::parse_from_str(&start, "%Y%m%d").ok()?, &Time { hours: 0, minutes: 0 }))
+    };
+    let mut components = Calendar::parse(events).components;
+    components.sort_by_cached_key(|c| {
+        let group = match config.sort_by {
+            SortBy::Date => Some(String::new()),
+            SortBy::Category => property(c, "CATEGORIES"),
+            SortBy::Summary => Some(property(c, "SUMMARY").unwrap_or_default()),
+        };
+        (group.is_none(), group, start(c))
+    });
+    components.into_iter().flatten().map(|line| format!("{line}\n")).collect()
+}
+
+/// Collapse the events that only differ by their date into the first of them, with an RDATE for each other date
+///
+/// The URL and source month of the first one are kept, and the month summaries stay separate as they are one per month.
+/// The `unpublished` events, left out by `drop_before` and `horizon_days`, are not output but still give their dates to
+/// the events they repeat.
+fn collapse_repeats(events: &str, unpublished: &str) -> String {
+    let is = |line: &str, name: &str| line.strip_prefix(name).is_some_and(|rest| rest.starts_with([':', ';']));
+    // Everything but the dates, which are the first eight characters of the values of DTSTART and DTEND
+    let key = |component: &[&str]| {
+        let without_date = |line: &str| line.split_once(':').map(|(name, value)| format!("{name}:{}", value.get(8..).unwrap_or_default()));
+        let lines = component.iter().filter(|l| !["UID", "DTSTAMP", "URL", "X-SHINBUKAN-SOURCE-MONTH"].iter().any(|name| is(l, name)));
+        lines.map(|l| if is(l, "DTSTART") || is(l, "DTEND") { without_date(l).unwrap_or_default() } else { l.to_string() }).collect::<Vec<_>>()
+    };
+    let components = Calendar::parse(events).components;
+    let mut firsts = BTreeMap::<Vec<String>, usize>::new();
+    let mut rdates = vec![Vec::new(); components.len()];
+    let mut repeated = vec![false; components.len()];
+    for (i, component) in components.iter().enumerate() {
+        if component.iter().any(|l| l.starts_with(&format!("UID:{MONTH_SUMMARY_UID}"))) {
+            continue;
+        }
+        let first = *firsts.entry(key(component)).or_insert(i);
+        if first != i {
+            let start = component.iter().find(|l| is(l, "DTSTART")).unwrap_or(&"");
+            rdates[first].push(format!("RDATE{}", start.strip_prefix("DTSTART").unwrap_or_default()));
+            repeated[i] = true;
+        }
+    }
+    for component in Calendar::parse(unpublished).components {
+        if let Some(first) = firsts.get(&key(&component)) {
+            let start = component.iter().find(|l| is(l, "DTSTART")).unwrap_or(&"");
+            rdates[*first].push(format!("RDATE{}", start.strip_prefix("DTSTART").unwrap_or_default()));
+        }
+    }
+    rdates.iter_mut().for_each(|rdates| rdates.sort());
+    let mut res = String::with_capacity(events.len());
+    for (i, component) in components.iter().enumerate().filter(|(i, _)| !repeated[*i]) {
+        for line in component {
+            res.push_str(&format!("{line}\n"));
+            if is(line, "DTEND") {
+                rdates[i].iter().for_each(|rdate| res.push_str(&format!("{rdate}\n")));
+            }
+        }
+    }
+    res
+}
+
+/// Suffix the UIDs of the events that reuse the UID of a previous one with their rank, as in `123-dup2@domain`
+fn suffix_uid_collisions(events: &str) -> String {
+    let mut seen = BTreeMap::<&str, usize>::new();
+    let mut res = String::with_capacity(events.len());
+    for line in events.lines() {
+        let Some(uid) = line.strip_prefix("UID:") else {
+            res.push_str(line);
+            res.push('\n');
+            continue;
+        };
+        let rank = seen.entry(uid).or_default();
+        *rank += 1;
+        match *rank {
+            1 => res.push_str(&format!("{line}\n")),
+            rank => res.push_str(&format!("UID:{}\n", suffixed_uid(uid, rank))),
+        }
+    }
+    res
+}
+
+/// UID of the event that comes `rank`-th among those sharing `uid`, as in `123-dup2@domain`
+pub(crate) fn suffixed_uid(uid: &str, rank: usize) -> String {
+    match uid.rsplit_once('@') {
+        Some((local, domain)) => format!("{local}-dup{rank}@{domain}"),
+        None => format!("{uid}-dup{rank}"),
+    }
+}
+
+/// A single VFREEBUSY covering the fetched months, with the events merged into busy periods
+fn render_freebusy(results: &[MonthResult], config: &Config) -> String {
+    let mut periods = results.iter().flat_map(|r| r.busy_periods(config)).collect::<Vec<_>>();
+    periods.sort();
+    let mut merged: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::with_capacity(periods.len());
+    for (from, to) in periods {
+        match merged.last_mut() {
+            Some((_, end)) if from <= *end => *end = (*end).max(to),
+            _ => merged.push((from, to)),
+        }
+    }
+    let midnight = Time { hours: 0, minutes: 0 };
+    let bound = |r: &MonthResult, next: u32| {
+        let date = r.first_day().ok()? + Months::new(next);
+        Some(to_utc(config.source_tz, date, &midnight).format("%Y%m%dT%H%M%SZ"))
+    };
+    let mut res = String::from("BEGIN:VFREEBUSY\n");
+    res.push_str(&format!("UID:freebusy@{}\n", config.uid_domain.trim_start_matches('@')));
+    res.push_str(&format!("DTSTAMP:{}\n", dtstamp(config)));
+    if let (Some(start), Some(end)) = (results.first().and_then(|r| bound(r, 0)), results.last().and_then(|r| bound(r, 1))) {
+        res.push_str(&format!("DTSTART:{start}\nDTEND:{end}\n"));
+    }
+    for (from, to) in merged {
+        res.push_str(&format!("FREEBUSY:{}/{}\n", from.format("%Y%m%dT%H%M%SZ"), to.format("%Y%m%dT%H%M%SZ")));
+    }
+    res.push_str("END:VFREEBUSY\n");
+    res
+}
+
+/// Add the VCALENDAR header and footer around the components, unless `events_only` is set, and fold the long lines
+fn wrap_calendar(body: &str, config: &Config) -> String {
+    let mut res = String::new();
+    if !config.events_only {
+        res.push_str("BEGIN:VCALENDAR\n");
+        res.push_str("VERSION:2.0\n");
+        let product = match config.instance_label.as_str() {
+            "" => "Shinbukan-ICS",
+            label => label,
+        };
+        res.push_str(&format!("PRODID:-//Shinbukan-ICS//{product}//\n"));
+        res.push_str("NAME:Shinbukan\n");
+        res.push_str("X-WR-CALNAME:Shinbukan\n");
+        if !config.cal_description.is_empty() {
+            // DESCRIPTION is the RFC 7986 property, X-WR-CALDESC the one most clients actually read
+            let description = escape_text(&config.cal_description);
+            res.push_str(&format!("DESCRIPTION:{description}\n"));
+            res.push_str(&format!("X-WR-CALDESC:{description}\n"));
+        }
+    }
+    res.push_str(body);
+    if !config.events_only {
+        res.push_str("END:VCALENDAR\n");
+    }
+    output::end_with(output::fold_lines(&res), config.final_newline)
+}
+
+/// Assemble one VCALENDAR per month, named `YYYY-MM.ics`
+///
+/// Months that could not be fetched, or whose credentials were rejected, are left out, so that the files from previous runs stay in place for them.
+pub fn render_split(results: &[MonthResult], config: &Config) -> Vec<(String, String)> {
+    results
+        .iter()
+        .filter(|r| !r.errors.iter().any(|e| matches!(e.kind(), ErrorKind::Fetch | ErrorKind::Credentials)))
+        .map(|r| (format!("{:04}-{:02}.ics", r.year, r.month), render_calendar(std::slice::from_ref(r), config)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calendar_fixtures() {
+        insta::glob!("fixtures/*.html", |path| {
+            let filename = path.file_name().unwrap().to_str().unwrap();
+            // Retrieve year/month from filename, ignoring any `-description` suffix
+            let mut yearmonth = filename.split_once('.').unwrap().0.split('-');
+            let year = yearmonth.next().unwrap().parse().unwrap();
+            let month = yearmonth.next().unwrap().parse().unwrap();
+            let mut result = MonthResult::new(year, month);
+
+            // Fixtures exercising non-default settings come with their configuration alongside
+            let config_path = path.with_extension("toml");
+            let config = match config_path.exists() {
+                true => Config::from_file(&config_path).unwrap(),
+                false => Config::default(),
+            };
+
+            // Read file and parse calendar
+            let input = std::fs::read_to_string(path).unwrap();
+            parse_calendar(&mut result, &input, &config);
+
+            // Assert the snapshot
+            insta::assert_debug_snapshot!(result);
+
+            // Generate the relevant ICS file
+            insta::assert_snapshot!(result.events_as_ics(&config));
+        })
+    }
+
+    #[test]
+    fn parse_files() {
+        let res = parse_file("src/fixtures/2024-06.html".as_ref(), &Config::default()).unwrap();
+        assert_eq!((res.year, res.month, res.event_count()), (2024, 6, 13));
+        // The configuration alongside the page wins, here merging the adjacent events
+        let res = parse_file("src/fixtures/2025-09-merge.html".as_ref(), &Config::default()).unwrap();
+        assert_eq!(res.event_count(), 5);
+        let res = parse_file("src/fixtures/2024-09-errors.html".as_ref(), &Config::default()).unwrap();
+        assert_eq!(res.errors.len(), 3);
+        // Only the requested days are parsed, without reporting the others as missing
+        let config = Config { only_days: vec![1, 13, 24], ..Config::default() };
+        let res = parse_file("src/fixtures/2024-06.html".as_ref(), &config).unwrap();
+        assert_eq!(res.events.iter().map(|e| e.day).collect::<Vec<_>>(), [13, 24]);
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let res = parse_file("src/fixtures/2024-09-errors.html".as_ref(), &config).unwrap();
+        assert_eq!(res.errors.iter().map(Error::day).collect::<Vec<_>>(), [Some(1)]);
+        let err = parse_file("src/fixtures/README.html".as_ref(), &Config::default()).unwrap_err();
+        assert_eq!(err.to_string(), "\"src/fixtures/README.html\" is not named after its month, as in 2024-06.html");
+        // Pages saved as served are in EUC-JP
+        let dir = std::env::temp_dir().join(format!("shinbukan-ics-parse-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let page = encoding_rs::EUC_JP.encode(include_str!("fixtures/2024-06.html")).0;
+        std::fs::write(dir.join("2024-06.html"), page).unwrap();
+        let res = parse_file(&dir.join("2024-06.html"), &Config::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let res = res.unwrap();
+        assert!(res.warnings.is_empty(), "{:?}", res.warnings);
+        let utf8 = parse_file("src/fixtures/2024-06.html".as_ref(), &Config::default()).unwrap();
+        assert_eq!(format!("{:?}", res.events), format!("{:?}", utf8.events));
+    }
+
+    #[test]
+    fn full_calendar() {
+        let results = [(2024, 12, include_str!("fixtures/2024-12.html")), (2025, 1, include_str!("fixtures/2025-01.html"))]
+            .into_iter()
+            .map(|(year, month, input)| {
+                let mut result = MonthResult::new(year, month);
+                parse_calendar(&mut result, input, &Config::default());
+                result
+            })
+            .collect::<Vec<_>>();
+        insta::assert_snapshot!(render_calendar(&results, &Config::default()));
+    }
+
+    #[test]
+    fn freebusy() {
+        let results = [(2024, 5, include_str!("fixtures/2024-05.html")), (2024, 6, include_str!("fixtures/2024-06.html"))]
+            .into_iter()
+            .map(|(year, month, input)| {
+                let mut result = MonthResult::new(year, month);
+                parse_calendar(&mut result, input, &Config::default());
+                result
+            })
+            .collect::<Vec<_>>();
+        let config = Config { format: OutputFormat::Freebusy, ..Config::default() };
+        let freebusy = render_calendar(&results, &config);
+        insta::assert_snapshot!(freebusy);
+
+        // All-day events are only busy when asked for
+        let with_full_day = render_calendar(&results, &Config { freebusy_full_day: true, ..config });
+        assert!(!freebusy.contains("FREEBUSY:20240501T150000Z/"));
+        assert!(with_full_day.contains("FREEBUSY:20240501T150000Z/"));
+    }
+
+    #[test]
+    fn split_output() {
+        let mut results = [(2024, 12, include_str!("fixtures/2024-12.html")), (2025, 1, include_str!("fixtures/2025-01.html"))]
+            .into_iter()
+            .map(|(year, month, input)| {
+                let mut result = MonthResult::new(year, month);
+                parse_calendar(&mut result, input, &Config::default());
+                result
+            })
+            .collect::<Vec<_>>();
+        let mut failed = MonthResult::new(2025, 2);
+        failed.error(Error::Fetch(anyhow::anyhow!("connection refused")));
+        results.push(failed);
+
+        let files = render_split(&results, &Config::default());
+        assert_eq!(files.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["2024-12.ics", "2025-01.ics"]);
+        for ((_, ics), result) in files.iter().zip(&results) {
+            assert!(ics.starts_with("BEGIN:VCALENDAR\n") && ics.ends_with("END:VCALENDAR\r\n"));
+            assert_eq!(ics.matches("BEGIN:VCALENDAR").count(), 1);
+            let month = format!("DTSTART:{:04}{:02}", result.year, result.month);
+            assert!(ics.lines().filter(|l| l.starts_with("DTSTART:")).all(|l| l.starts_with(&month)));
+            assert_eq!(ics.matches("BEGIN:VEVENT").count(), result.events.len());
+        }
+        // Together, the files hold the same events as the combined calendar
+        let combined = render_calendar(&results, &Config::default());
+        assert_eq!(files.iter().map(|(_, ics)| ics.matches("BEGIN:VEVENT").count()).sum::<usize>(), combined.matches("BEGIN:VEVENT").count());
+    }
+
+    #[test]
+    fn events_only() {
+        let config = Config { events_only: true, ..Config::default() };
+        let mut result = MonthResult::new(2024, 12);
+        parse_calendar(&mut result, include_str!("fixtures/2024-12.html"), &config);
+        // Long Japanese lines get folded between characters, with the fragment as with the full calendar
+        let long = "1-5 鳴尾浜 柔道場/年末大掃除、道場の畳と窓と神棚の清掃、終了後に鏡開きの準備も行います";
+        result.events.extend(parse_single_cell(2024, 12, 28, long, &config).events);
+        let fragment = render_calendar(&[result], &config);
+        assert!(fragment.starts_with("BEGIN:VEVENT\n") && fragment.ends_with("END:VEVENT\r\n"));
+        assert!(fragment.lines().all(|l| l.len() <= 75));
+        assert!(fragment.contains("\n "));
+        insta::assert_snapshot!(fragment);
+    }
+
+    #[test]
+    fn instance_label() {
+        let result = parse_single_cell(2024, 6, 1, "5-7 稽古", &Config::default());
+        let config = Config { instance_label: String::from("prod-a"), ..Config::default() };
+        assert!(render_calendar(std::slice::from_ref(&result), &config).contains("\nPRODID:-//Shinbukan-ICS//prod-a//\n"));
+        assert!(render_calendar(&[result], &Config::default()).contains("\nPRODID:-//Shinbukan-ICS//Shinbukan-ICS//\n"));
+    }
+
+    #[test]
+    fn final_newline() {
+        let result = parse_single_cell(2024, 6, 1, "5-7 稽古", &Config::default());
+        let render = |final_newline, events_only| {
+            render_calendar(std::slice::from_ref(&result), &Config { final_newline, events_only, ..Config::default() }).into_bytes()
+        };
+        // A single CRLF by default, the other lines keeping their LF
+        assert_eq!(Config::default().final_newline, FinalNewline::Crlf);
+        assert!(render(FinalNewline::Crlf, false).ends_with(b"\nEND:VEVENT\nEND:VCALENDAR\r\n"));
+        assert_eq!(render(FinalNewline::Crlf, false).iter().filter(|b| **b == b'\r').count(), 1);
+        assert!(render(FinalNewline::Omit, false).ends_with(b"\nEND:VEVENT\nEND:VCALENDAR"));
+        assert!(render(FinalNewline::Crlf, true).ends_with(b".html\nEND:VEVENT\r\n"));
+    }
+
+    #[test]
+    fn missing_days_are_errors() {
+        // A grid cut short after the first week, as opposed to the blank cells of a month without a schedule
+        let input = include_str!("fixtures/2025-05-blank.html");
+        let cut = input.find("</tr>\n<tr valign=\"top\">\n<td bgcolor=\"#ffc0c0\"><br></td>").unwrap();
+        let mut result = MonthResult::new(2025, 5);
+        parse_calendar(&mut result, &input[..cut], &Config::default());
+        let errors = result.errors().iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(errors.len(), 28);
+        assert_eq!(errors[0], "Did not parse day 4");
+    }
+
+    #[test]
+    fn explicit_months_bypass_the_window() {
+        let months = |config: &Config| months_to_fetch(config).unwrap().into_iter().map(|m| (m.year, m.month)).collect::<Vec<_>>();
+        let config = Config {
+            months: vec!["2025-01".parse().unwrap(), "2024-03".parse().unwrap(), "2025-01".parse().unwrap()],
+            ..Config::default()
+        };
+        assert_eq!(months(&config), [(2024, 3), (2025, 1)]);
+        assert_eq!(months(&Config::default()).len(), 15);
+        assert_eq!(months(&Config { months_back: 0, months_ahead: 0, ..Config::default() }).len(), 1);
+    }
+
+    #[test]
+    fn window_follows_now() {
+        let config = Config {
+            now: "2025-01-31T23:00:00Z".parse().unwrap(),
+            months_back: 2,
+            months_ahead: 1,
+            window_tz: chrono_tz::UTC,
+            ..Config::default()
+        };
+        let months = months_to_fetch(&config).unwrap().into_iter().map(String::from).collect::<Vec<_>>();
+        assert_eq!(months, ["2024-11", "2024-12", "2025-01", "2025-02"]);
+    }
+
+    #[test]
+    fn window_in_dojo_timezone() {
+        // Already February 1st in Tokyo
+        let config = Config { now: "2025-01-31T23:00:00Z".parse().unwrap(), months_back: 2, months_ahead: 1, ..Config::default() };
+        let months = months_to_fetch(&config).unwrap().into_iter().map(String::from).collect::<Vec<_>>();
+        assert_eq!(months, ["2024-12", "2025-01", "2025-02", "2025-03"]);
+        assert_eq!(config.today(), NaiveDate::from_ymd_opt(2025, 2, 1).unwrap());
+        // Still January 31st in Tokyo
+        let config = Config { now: "2025-01-31T14:59:59Z".parse().unwrap(), ..config };
+        assert_eq!(config.today(), NaiveDate::from_ymd_opt(2025, 1, 31).unwrap());
+    }
+
+    #[test]
+    fn implausible_years() {
+        let config = Config { months: vec!["0202-06".parse().unwrap(), "2024-06".parse().unwrap()], ..Config::default() };
+        assert_eq!(
+            months_to_fetch(&config).unwrap_err().to_string(),
+            "Refusing to fetch 0202-06, as it is outside of the plausible years 2000-2100",
+        );
+        let config = Config { now: "2100-12-01T00:00:00Z".parse().unwrap(), ..Config::default() };
+        assert!(months_to_fetch(&config).is_err());
+        assert!(months_to_fetch(&Config { max_year: 2101, ..config }).is_ok());
+    }
+
+    #[test]
+    fn fetch_and_emit_ranges_are_independent() {
+        let fixtures = [(2024, 3), (2024, 4), (2024, 5), (2024, 6), (2024, 7)]
+            .map(|(year, month)| (YearMonth { year, month }, std::fs::read_to_string(format!("src/fixtures/{year}-{month:02}.html")).unwrap()));
+        let config = Config {
+            now: "2024-06-12T12:00:00Z".parse().unwrap(),
+            months_back: 3,
+            months_ahead: 1,
+            drop_before: Some("today-7d".parse().unwrap()),
+            ..Config::default()
+        };
+
+        let months = months_to_fetch(&config).unwrap();
+        assert_eq!(months, fixtures.iter().map(|(m, _)| *m).collect::<Vec<_>>());
+        let results = fixtures
+            .iter()
+            .map(|(m, input)| {
+                let mut result = MonthResult::new(m.year, m.month);
+                parse_calendar(&mut result, input, &config);
+                result
+            })
+            .collect::<Vec<_>>();
+
+        // The earlier months were parsed, but only events from 2024-06-05 onwards get emitted
+        assert!(!results[0].events.is_empty());
+        let ics = render_calendar(&results, &config);
+        let mut starts = ics.lines().filter_map(|l| l.strip_prefix("DTSTART:")).peekable();
+        assert!(starts.peek().unwrap().starts_with("202406"));
+        assert!(starts.all(|s| s >= "20240605"));
+        // The dropped months still count as repeats of the emitted events, as RDATEs
+        let repeats = Config { rdate_repeats: true, ..config };
+        let ics = render_calendar(&results, &repeats);
+        assert!(ics.lines().filter_map(|l| l.strip_prefix("DTSTART:")).all(|s| s >= "20240605"));
+        let rdates = ics.lines().filter_map(|l| l.strip_prefix("RDATE:")).collect::<Vec<_>>();
+        assert!(rdates.iter().any(|d| d < &"20240605"), "{rdates:?}");
+        assert!(rdates.iter().any(|d| d.starts_with("202403")), "{rdates:?}");
+    }
+
+    #[test]
+    fn drop_before_filters_past_events() {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+
+        let drop_before = |date| Config { drop_before: Some(DateAnchor::Date(date)), ..Config::default() };
+        let ics = result.events_as_ics(&drop_before(NaiveDate::from_ymd_opt(2024, 6, 10).unwrap()));
+        assert!(!ics.contains("DTSTART:20240609"));
+        assert!(ics.contains("DTSTART:20240610"));
+        assert_eq!(result.events_as_ics(&drop_before(NaiveDate::from_ymd_opt(2024, 7, 1).unwrap())), "");
+    }
+
+    #[test]
+    fn horizon_days() {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+
+        // Events on the last day of the horizon are kept, the ones after it are not
+        let config = Config {
+            horizon_days: Some(3),
+            now: "2024-06-13T23:00:00Z".parse().unwrap(),
+            window_tz: chrono_tz::UTC,
+            ..Config::default()
+        };
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("DTSTART:20240616"));
+        assert!(!ics.contains("DTSTART:20240617"));
+        assert!(ics.contains("DTSTART:20240610"));
+        let config = Config { horizon_days: Some(0), now: "2024-06-23T00:00:00Z".parse().unwrap(), ..config };
+        assert!(result.events_as_ics(&config).contains("DTSTART:20240623"));
+        assert!(!result.events_as_ics(&config).contains("DTSTART:20240624"));
+    }
+
+    #[test]
+    fn provenance() {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+        result.fetched_at = Some("2024-06-12T03:04:05Z".parse().unwrap());
+        let config = Config { provenance: true, ..Config::default() };
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("DESCRIPTION:Source: http://brionac.s17.xrea.com/schedule/homepage/homepage/calendar/2024/202406.html\\nFetched: 2024-06-12T03:04:05Z\n"));
+        assert!(!result.events_as_ics(&Config::default()).contains("DESCRIPTION"));
+    }
+
+    #[test]
+    fn errors_in_calendar() {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+        result.error(Error::MissingDay { day: 3 });
+        result.error(Error::DuplicateDay { day: 4 });
+        assert!(!result.events_as_ics(&Config::default()).contains("shinbukan-ics: "));
+
+        let config = Config { include_errors_in_calendar: true, ..Config::default() };
+        let ics = result.events_as_ics(&config);
+        let error_event = &ics[ics.rfind("BEGIN:VEVENT").unwrap()..];
+        assert!(error_event.contains("DTSTART;VALUE=DATE:20240601\n"));
+        assert!(error_event.contains("SUMMARY:shinbukan-ics: 2 errors while processing this month\n"));
+        assert!(error_event.contains("DESCRIPTION:Did not parse day 3\\nParsed day 4 twice\n"));
+    }
+
+    #[test]
+    fn text_escaping() {
+        assert_eq!(escape_text("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+    }
+
+    #[test]
+    fn organizer_and_contact() {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+        let config = Config {
+            organizer: Some("Dojo, Tokyo <mailto:info@example.org>".parse().unwrap()),
+            contact: Some("03-0000-0000".to_owned()),
+            ..Config::default()
+        };
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("URL:http://brionac.s17.xrea.com/schedule/homepage/homepage/calendar/2024/202406.html\nORGANIZER;CN=\"Dojo, Tokyo\":mailto:info@example.org\nCONTACT:03-0000-0000\nEND:VEVENT\n"));
+    }
+
+    #[test]
+    fn max_summary_len() {
+        let result = parse_single_cell(2024, 6, 1, "鳴尾浜 柔道場/自主稽古、道着持参のこと", &Config::default());
+        let config = Config { max_summary_len: Some(7), ..Config::default() };
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("SUMMARY:鳴尾浜 柔道場…\n"), "{ics}");
+        assert!(ics.contains("DESCRIPTION:鳴尾浜 柔道場/自主稽古、道着持参のこと\n"), "{ics}");
+
+        // Summaries that fit are left alone
+        let config = Config { max_summary_len: Some(20), ..Config::default() };
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("SUMMARY:鳴尾浜 柔道場/自主稽古、道着持参のこと\n"), "{ics}");
+        assert!(!ics.contains("DESCRIPTION:"), "{ics}");
+    }
+
+    #[test]
+    fn apple_location() {
+        let venues = Config { venues: vec!["本部道場".to_owned()], ..Config::default() };
+        let result = parse_single_cell(2024, 6, 1, "本部道場 19:00-21:00 稽古", &venues);
+        let config = Config { geo: Some("35.6812,139.7671".parse().unwrap()), ..venues.clone() };
+        assert!(!result.events_as_ics(&config).contains("X-APPLE-STRUCTURED-LOCATION"));
+
+        let config = Config { apple_location: true, ..config };
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("LOCATION:本部道場\nX-APPLE-STRUCTURED-LOCATION;VALUE=URI;X-ADDRESS=\"本部道場\";X-APPLE-RADIUS=70;X-TITLE=\"本部道場\":geo:35.6812,139.7671\n"), "{ics}");
+
+        // Without a location there is nothing to pin
+        let result = parse_single_cell(2024, 6, 1, "19:00-21:00 稽古", &config);
+        assert!(!result.events_as_ics(&config).contains("X-APPLE-STRUCTURED-LOCATION"));
+    }
+
+    #[test]
+    fn colors() {
+        let result = parse_single_cell(2024, 6, 1, "19:00-21:00 自主稽古<br>13:00-17:00 合同稽古<br>合宿", &Config::default());
+        assert!(!result.events_as_ics(&Config::default()).contains("COLOR:"));
+
+        let config = Config::from_toml(
+            r#"
+                event-color = "gray"
+                [category-colors]
+                "稽古" = "steelblue"
+                "自主稽古" = "orange"
+            "#,
+        )
+        .unwrap();
+        let ics = result.events_as_ics(&config);
+        let events = ics.split("BEGIN:VEVENT").skip(1).collect::<Vec<_>>();
+        assert!(events[0].contains("CATEGORIES:自主稽古\nCOLOR:orange\n"), "{}", events[0]);
+        assert!(events[1].contains("CATEGORIES:稽古\nCOLOR:steelblue\n"), "{}", events[1]);
+        assert!(!events[2].contains("CATEGORIES:") && events[2].contains("COLOR:gray\n"), "{}", events[2]);
+    }
+
+    #[test]
+    fn source_tz() {
+        let config = Config { source_tz: chrono_tz::Europe::Paris, ..Config::default() };
+        let result = parse_single_cell(2024, 6, 1, "19:00-21:00 稽古", &config);
+        assert!(result.events_as_ics(&config).contains("DTSTART:20240601T170000Z\nDTEND:20240601T190000Z\n"));
+
+        // 2:30 does not exist on the day Paris switches to summer time
+        let result = parse_single_cell(2024, 3, 31, "2:30-12:00 稽古", &Config { afternoon_before: 0, ..config.clone() });
+        assert!(result.events_as_ics(&config).contains("DTSTART:20240331T013000Z\n"));
+    }
+
+    #[test]
+    fn invalid_month() {
+        let mut result = MonthResult::new(2024, 13);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+        assert_eq!(result.errors().len(), 1);
+        assert_eq!(result.errors()[0].to_string(), "2024-13 is not a valid month");
+        assert!(result.events_as_ics(&Config::default()).is_empty());
+    }
+
+    #[test]
+    fn uid_domain() {
+        let result = parse_single_cell(2024, 10, 2, "19:00-21:00 稽古", &Config::default());
+        assert!(result.events_as_ics(&Config::default()).contains("UID:2122538360428261964@shinbukan-ics\n"));
+
+        let config = Config { uid_domain: "iaido.example".to_owned(), ..Config::default() };
+        assert!(result.events_as_ics(&config).contains("UID:2122538360428261964@iaido.example\n"));
+        // Domains given along with their `@` are not doubled
+        let config = Config { uid_domain: "@iaido.example".to_owned(), ..Config::default() };
+        assert!(result.events_as_ics(&config).contains("UID:2122538360428261964@iaido.example\n"));
+    }
+
+    #[test]
+    fn url_property() {
+        let result = parse_single_cell(2024, 6, 1, "19:00-21:00 稽古", &Config::default());
+        let config = Config { no_url_property: true, ..Config::default() };
+        insta::assert_snapshot!(result.events_as_ics(&config));
+        let config = Config { event_url: Some("https://dojo.example/{year}/{month}#{day}".to_owned()), ..Config::default() };
+        insta::assert_snapshot!(result.events_as_ics(&config));
+    }
+
+    #[test]
+    fn uid_collision() {
+        // The same line on the same day of two months, and twice in the same cell
+        let june = parse_single_cell(2024, 6, 1, "1-5 稽古", &Config::default());
+        let july = parse_single_cell(2024, 7, 1, "1-5 稽古<br>1-5 稽古", &Config::default());
+        let results = [june, july];
+        let uid = results[0].events[0].uid("shinbukan-ics");
+        assert_eq!(uid_collisions(&results, &Config::default()), [(uid.clone(), 3)]);
+
+        let uids = |config: &Config| render_calendar(&results, config).lines().filter_map(|l| l.strip_prefix("UID:").map(str::to_owned)).collect::<Vec<_>>();
+        let (local, domain) = uid.split_once('@').unwrap();
+        assert_eq!(uids(&Config::default()), [uid.clone(), format!("{local}-dup2@{domain}"), format!("{local}-dup3@{domain}")]);
+        let config = Config { on_uid_collision: UidCollision::Ignore, ..Config::default() };
+        assert_eq!(uids(&config), [uid.clone(), uid.clone(), uid.clone()]);
+        assert!(uid_collisions(&results[..1], &Config::default()).is_empty());
+    }
+
+    #[test]
+    fn pdf_attachments() {
+        let result = parse_single_cell(2024, 6, 1, r#"<a href="/flyers/seminar.pdf#page=2">1-5 講習会</a>"#, &Config::default());
+        let ics = result.events_as_ics(&Config::default());
+        assert!(ics.contains("ATTACH;FMTTYPE=application/pdf:http://brionac.s17.xrea.com/flyers/seminar.pdf#page=2\n"), "{ics}");
+        // Other links are only read for their text
+        let result = parse_single_cell(2024, 6, 1, r#"<a href="seminar.html">1-5 講習会</a>"#, &Config::default());
+        assert_eq!(result.events[0].text, "講習会");
+        assert!(!result.events_as_ics(&Config::default()).contains("ATTACH"));
+    }
+
+    #[test]
+    fn html_description() {
+        let cell = r#"5-7 稽古 <font color="red">中止</font> <a href="notice.html">案内 & 地図</a>"#;
+        let result = parse_single_cell(2024, 6, 1, cell, &Config::default());
+        // Opt-in, as most clients only show the plain DESCRIPTION
+        assert!(!result.events_as_ics(&Config::default()).contains("X-ALT-DESC"));
+        let config = Config { html_description: true, ..Config::default() };
+        let ics = result.events_as_ics(&config);
+        let description = ics.lines().filter(|l| l.starts_with("DESCRIPTION:") || l.starts_with("X-ALT-DESC;")).collect::<Vec<_>>();
+        insta::assert_snapshot!(description.join("\n"));
+        // Events without markup keep their plain DESCRIPTION only
+        let result = parse_single_cell(2024, 6, 1, "5-7 稽古", &config);
+        assert!(!result.events_as_ics(&config).contains("X-ALT-DESC"));
+    }
+
+    #[test]
+    fn sort_by() {
+        let mut config = Config::from_toml("[category-colors]\n\"稽古\" = \"red\"\n\"講習会\" = \"blue\"").unwrap();
+        let results = [
+            parse_single_cell(2024, 6, 1, "5-7 稽古<br>1-3 講習会<br>合宿", &config),
+            parse_single_cell(2024, 7, 1, "5-7 稽古<br>1-3 講習会", &config),
+        ];
+        let order = |config: &Config| {
+            let ics = render_calendar(&results, config);
+            let starts = ics.lines().filter_map(|l| l.strip_prefix("DTSTART").map(|s| &s.rsplit_once(':').unwrap().1[..8]));
+            let summaries = ics.lines().filter_map(|l| l.strip_prefix("SUMMARY:"));
+            starts.zip(summaries).map(|(start, summary)| format!("{start} {summary}")).collect::<Vec<_>>()
+        };
+        assert_eq!(order(&config), ["20240601 合宿", "20240601 講習会", "20240601 稽古", "20240701 講習会", "20240701 稽古"]);
+        // Each category is in chronological order, with the events without one last
+        config.sort_by = SortBy::Category;
+        assert_eq!(order(&config), ["20240601 稽古", "20240701 稽古", "20240601 講習会", "20240701 講習会", "20240601 合宿"]);
+        config.sort_by = SortBy::Summary;
+        assert_eq!(order(&config), ["20240601 合宿", "20240601 稽古", "20240701 稽古", "20240601 講習会", "20240701 講習会"]);
+    }
+
+    #[test]
+    fn rdate_repeats() {
+        let config = Config { rdate_repeats: true, ..Config::default() };
+        let results = [(2027, 3, include_str!("fixtures/2027-03-seminars.html")), (2027, 4, include_str!("fixtures/2027-04-seminars.html"))].map(
+            |(year, month, input)| {
+                let mut result = MonthResult::new(year, month);
+                parse_calendar(&mut result, input, &config);
+                result
+            },
+        );
+        let ics = render_calendar(&results, &config);
+        insta::assert_snapshot!(ics);
+        // Each event keeps its own occurrences
+        assert_eq!(ics.matches("BEGIN:VEVENT").count() + ics.matches("RDATE").count(), results.iter().map(MonthResult::event_count).sum::<usize>());
+    }
+
+    #[test]
+    fn short_events() {
+        let contents = "19:00-19:00 稽古<br>5-7 稽古";
+        // Without a minimum, the zero-minute event is only taken to end before it starts
+        let result = parse_single_cell(2024, 6, 1, contents, &Config::default());
+        assert_eq!(result.events.len(), 2);
+        assert!(matches!(result.warnings[..], [Warning::EndsBeforeStart { .. }]));
+        let config = Config { min_interval_mins: Some(5), on_short_event: ShortEvents::Drop, ..Config::default() };
+        let result = parse_single_cell(2024, 6, 1, contents, &config);
+        assert_eq!(result.events.len(), 1);
+        assert!(matches!(result.warnings[..], [Warning::ShortEventDropped { minutes: 0, .. }]));
+    }
+
+    #[test]
+    fn extra_events() {
+        let config = Config {
+            extra_events: ["2024-06-15 休館日", "2024-06-02 13:00-17:00 鳴尾浜 柔道場/自主稽古", "2024-07-15 海の日"].map(|e| e.parse().unwrap()).into(),
+            ..Config::default()
+        };
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &config);
+        let ics = render_calendar(&[result], &config);
+        // Only the holiday is added, the page already having the session and July not being fetched
+        let extra = ics.lines().filter(|l| l.starts_with("UID:") && l.ends_with("@extra.shinbukan-ics")).collect::<Vec<_>>();
+        assert_eq!(extra.len(), 1);
+        // They are not from the page, and do not link to it
+        let holiday = ics.split("BEGIN:VEVENT").find(|c| c.contains("SUMMARY:休館日")).unwrap();
+        assert!(!holiday.contains("\nURL:"), "{holiday}");
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 14);
+        // Sorted among the events of the page
+        let summaries = ics.lines().filter_map(|l| l.strip_prefix("SUMMARY:")).collect::<Vec<_>>();
+        let holiday = summaries.iter().position(|s| *s == "休館日").unwrap();
+        let starts = ics.lines().filter_map(|l| l.strip_prefix("DTSTART").map(|s| &s.rsplit_once(':').unwrap().1[..8])).collect::<Vec<_>>();
+        assert_eq!(starts[holiday], "20240615");
+        assert!(starts.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn source_month() {
+        let config = Config { source_month: true, ..Config::default() };
+        let result = parse_single_cell(2024, 6, 1, "5-7 稽古", &config);
+        insta::assert_snapshot!(result.events_as_ics(&config));
+    }
+
+    #[test]
+    fn compact() {
+        let config = Config {
+            compact: true,
+            venues: vec![String::from("本部道場")],
+            provenance: true,
+            source_month: true,
+            category_colors: BTreeMap::from([(String::from("稽古"), "red".parse().unwrap())]),
+            ..Config::default()
+        };
+        // Compact summaries still get folded when they are long
+        let contents = "本部道場 5-7 稽古<br>本部道場 1-3 昇段審査に向けた投の形と固の形の稽古、受けと取りを交代しながら<br>※胴着持参";
+        let result = parse_single_cell(2024, 6, 1, contents, &config);
+        insta::assert_snapshot!(render_calendar(&[result], &Config { events_only: true, ..config }));
+    }
+
+    #[test]
+    fn display_tz() {
+        // Paris switches to summer time in the night between these two days
+        let page = r#"<table summary="日程"><tr><td>30<br>5-7 稽古<br></td><td>31<br>5-7 稽古<br></td></tr></table>"#;
+        let mut result = MonthResult::new(2024, 3);
+        parse_calendar(&mut result, page, &Config::default());
+        let config = Config { display_tz: Some(chrono_tz::Europe::Paris), ..Config::default() };
+        let ics = render_calendar(&[result], &config);
+        let times = ics.lines().filter(|l| l.starts_with("DTSTART;") || l.starts_with("DTEND;")).collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            [
+                "DTSTART;TZID=Europe/Paris:20240330T090000",
+                "DTEND;TZID=Europe/Paris:20240330T110000",
+                "DTSTART;TZID=Europe/Paris:20240331T100000",
+                "DTEND;TZID=Europe/Paris:20240331T120000",
+            ],
+        );
+        assert!(ics.contains("\nBEGIN:VTIMEZONE\nTZID:Europe/Paris\n"), "{ics}");
+        assert!(ics.contains("\nDTSTART:20240331T020000\nTZOFFSETFROM:+0100\nTZOFFSETTO:+0200\n"), "{ics}");
+    }
+
+    #[test]
+    fn explain() {
+        let config = Config { explain: true, venues: vec![String::from("鳴尾浜")], ..Config::default() };
+        let cell = "6/4 鳴尾浜 ・5-7 稽古<br>10:00-12:00，1-3 講習会<br>終日 休館<br>合宿";
+        let result = parse_single_cell(2024, 6, 4, cell, &config);
+        insta::assert_snapshot!(result.explanations().concat());
+        // Only the explanations differ
+        let quiet = Config { explain: false, ..config.clone() };
+        assert_eq!(result.events_as_ics(&config), parse_single_cell(2024, 6, 4, cell, &quiet).events_as_ics(&quiet));
+        assert!(parse_single_cell(2024, 6, 4, cell, &quiet).explanations().is_empty());
+    }
+
+    #[test]
+    fn day_numbers() {
+        assert_eq!(parse_day_number("15"), Some(15));
+        assert_eq!(parse_day_number("15日"), Some(15));
+        assert_eq!(parse_day_number("15（金）"), Some(15));
+        assert_eq!(parse_day_number("15日 ( 金 )"), Some(15));
+        // Events written next to the number are not taken for it
+        assert_eq!(parse_day_number("15 稽古"), None);
+        assert_eq!(parse_day_number("15(稽古)"), None);
+        assert_eq!(parse_day_number("日"), None);
+        assert_eq!(parse_day_number("0"), None);
+        assert_eq!(parse_day_number("32日"), None);
+        assert_eq!(parse_day_number("2024"), None);
+    }
+
+    #[test]
+    fn invalid_time_ranges_are_errors() {
+        let result = parse_single_cell(2024, 6, 4, "19:-21:00 稽古", &Config::default());
+        assert!(result.errors.iter().any(|e| matches!(e, Error::InvalidTimeRange { day: 4, range } if range == "19:-21:00")), "{:?}", result.errors);
+        assert!(matches!(&result.events[..], [Event { time: EventTime::FullDay, .. }]), "{:?}", result.events);
+    }
+
+    #[test]
+    fn days_past_the_month_are_errors() {
+        let result = parse_single_cell(2024, 6, 31, "19:00-21:00 稽古", &Config::default());
+        assert!(result.events.is_empty());
+        assert!(matches!(result.errors[..], [Error::DayOutOfRange { day: 31, days_in_month: 30, .. }, ..]), "{:?}", result.errors);
+    }
+
+    #[test]
+    fn month_summary_event() {
+        let mut result = MonthResult::new(2024, 6);
+        parse_calendar(&mut result, include_str!("fixtures/2024-06.html"), &Config::default());
+        let config = Config { month_summary_event: true, ..Config::default() };
+        let ics = result.events_as_ics(&config);
+        let summary = &ics[ics.rfind("BEGIN:VEVENT").unwrap()..];
+        insta::assert_snapshot!(summary);
+        assert_eq!(config.locale.month_summary(1), "今月の稽古: 1回");
+        assert_eq!(Locale::En.month_summary(1), "1 class this month");
+    }
+
+    #[test]
+    fn weekday_prefix() {
+        let result = parse_single_cell(2024, 6, 4, "19:00-21:00 稽古", &Config::default());
+        let summary = |weekday_prefix| {
+            let ics = result.events_as_ics(&Config { weekday_prefix, ..Config::default() });
+            ics.lines().find_map(|l| l.strip_prefix("SUMMARY:")).unwrap().to_owned()
+        };
+        assert_eq!(summary(None), "稽古");
+        assert_eq!(summary(Some(Locale::Ja)), "(火) 稽古");
+        assert_eq!(summary(Some(Locale::En)), "(Tue) 稽古");
+    }
+
+    #[test]
+    fn timezone_output_display() {
+        let config = Config { timezone_output_display: true, ..Config::default() };
+        let result = parse_single_cell(2024, 6, 4, "7:00-21:30 稽古", &config);
+        let ics = result.events_as_ics(&config);
+        assert!(ics.contains("\nDTSTART:20240604T100000Z\nDTEND:20240604T123000Z\nSUMMARY:稽古 (JST 19:00-21:30)\n"));
+        let result = parse_single_cell(2024, 6, 4, "合宿", &config);
+        assert!(result.events_as_ics(&config).contains("\nSUMMARY:合宿\n"));
+    }
+
+    #[test]
+    fn pin_dtstamp() {
+        let config = Config { pin_dtstamp: true, now: "2024-06-12T12:34:56Z".parse().unwrap(), ..Config::default() };
+        let result = parse_single_cell(2024, 6, 1, "19:00-21:00 稽古", &config);
+        assert!(result.events_as_ics(&config).contains("\nDTSTAMP:20240612T000000Z\n"));
+    }
+
+    #[test]
+    fn fullday_as_timed() {
+        let result = parse_single_cell(2024, 6, 1, "合宿", &Config::default());
+        assert!(result.events_as_ics(&Config::default()).contains("DTSTART;VALUE=DATE:20240601\n"));
+        let config = Config { fullday_as_timed: Some("09:00-17:30".parse().unwrap()), ..Config::default() };
+        insta::assert_snapshot!(result.events_as_ics(&config));
+    }
+
+    /// Parse a page made of a single day cell with the given contents
+    fn parse_single_cell(year: usize, month: usize, day: usize, contents: &str, config: &Config) -> MonthResult {
+        let mut result = MonthResult::new(year, month);
+        let page = format!(r#"<table summary="日程"><tr><td>{day}<br>{contents}<br></td></tr></table>"#);
+        parse_calendar(&mut result, &page, config);
+        result
+    }
+
+    /// Write a 24h time the way the upstream calendar does, with afternoon hours in 12h format
+    fn as_written(minutes: usize, config: &Config) -> String {
+        let (hours, minutes) = (minutes / 60, minutes % 60);
+        let hours = if hours > 12 && hours - 12 < config.afternoon_before { hours - 12 } else { hours };
+        match minutes {
+            0 => format!("{hours}"),
+            _ => format!("{hours}:{minutes:02}"),
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn parse_time_roundtrips(hours in 0..48usize, minutes in 0..60usize) {
+            let time = parse_time(&format!("{hours}:{minutes:02}")).unwrap();
+            proptest::prop_assert_eq!((time.hours, time.minutes), (hours, minutes));
+            let time = parse_time(&format!("{hours}")).unwrap();
+            proptest::prop_assert_eq!((time.hours, time.minutes), (hours, 0));
+        }
+
+        #[test]
+        fn lines_never_panic(line in "[0-9:~-]{0,12}( 稽古)?|\\PC*") {
+            let result = parse_single_cell(2024, 6, 4, &line, &Config::default());
+            result.events_as_ics(&Config::default());
+        }
+
+        // Ends past midnight are written on from 24:00, and roll over to the next day
+        #[test]
+        fn ends_past_midnight_roll_over(from in (20 * 60)..(24 * 60usize), len in 1..(6 * 60usize)) {
+            let config = Config::default();
+            let to = from + len;
+            let contents = format!("{}:{:02}-{}:{:02} 稽古", from / 60, from % 60, to / 60, to % 60);
+            let result = parse_single_cell(2024, 6, 4, &contents, &config);
+            proptest::prop_assert!(matches!(&result.events[..], [Event { time: EventTime::Timed { .. }, .. }]), "{:?}", result.events);
+
+            let ics = result.events_as_ics(&config);
+            let property = |name: &str| {
+                let value = ics.lines().find_map(|l| l.strip_prefix(name)).unwrap();
+                chrono::NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ").unwrap()
+            };
+            let (start, end) = (property("DTSTART:"), property("DTEND:"));
+            proptest::prop_assert_eq!((end - start).num_minutes(), i64::try_from(len).unwrap());
+        }
+
+        // The 12h heuristic cannot represent times before `afternoon_before` in the morning, nor ranges crossing
+        // midnight, so only generate ranges from 8:00 to 19:59 for which it is unambiguous
+        #[test]
+        fn timed_events_roundtrip(
+            day in 1..=28usize,
+            from in (8 * 60)..(20 * 60 - 1usize),
+            len in 1..(12 * 60usize),
+            sep in proptest::sample::select(vec!['-', '~']),
+        ) {
+            let to = std::cmp::min(from + len, 20 * 60 - 1);
+            let config = Config::default();
+            let contents = format!("{}{sep}{} 稽古", as_written(from, &config), as_written(to, &config));
+            let result = parse_single_cell(2024, 6, day, &contents, &config);
+
+            let [Event { day: parsed_day, time: EventTime::Timed { from: parsed_from, to: parsed_to }, text, .. }] = &result.events[..] else {
+                return Err(proptest::test_runner::TestCaseError::fail(format!("{contents:?} parsed as {:?}", result.events)));
+            };
+            proptest::prop_assert_eq!(*parsed_day, day);
+            proptest::prop_assert!(parsed_from.hours < 24 && parsed_from.minutes < 60);
+            proptest::prop_assert!(parsed_to.hours < 24 && parsed_to.minutes < 60);
+            proptest::prop_assert_eq!(parsed_from.hours * 60 + parsed_from.minutes, from);
+            proptest::prop_assert_eq!(parsed_to.hours * 60 + parsed_to.minutes, to);
+            proptest::prop_assert_eq!(text, "稽古");
+
+            let ics = result.events_as_ics(&config);
+            let property = |name: &str| {
+                let value = ics.lines().find_map(|l| l.strip_prefix(name)).unwrap();
+                chrono::NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ").unwrap()
+            };
+            let (start, end) = (property("DTSTART:"), property("DTEND:"));
+            proptest::prop_assert!(start < end);
+            proptest::prop_assert_eq!((end - start).num_minutes(), i64::try_from(to - from).unwrap());
+        }
+    }
+}
@@ -1,315 +1,935 @@
-use anyhow::anyhow;
-use chrono::{Utc, Months, Datelike, NaiveDate, TimeZone};
-use futures::StreamExt;
-use scraper::Node;
-use std::hash::{Hash, Hasher};
-
-const NUM_MONTHS: u32 = 15; // 2 months of "going back", plus one year, plus one month
-
-#[derive(Debug, Hash)]
-struct Time {
-    hours: usize,
-    minutes: usize,
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::CommandFactory;
+use shinbukan_ics::google::{self, GoogleCalendar};
+use shinbukan_ics::{check_event_count, ErrorKind, months_to_fetch, parse_file, render_calendar, render_split, merge_into_file, uid_collisions, write_atomically, write_calendar, Color, Config, Credentials, DateAnchor, Error, ExtraEvent, FinalNewline, Geo, HttpFetcher, Locale, MonthResult, Organizer, OutputFormat, ShortEvents, SortBy, State, Status, summary_table, TimeRange, UidCollision, YearMonth};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Generate an ICS calendar from the Shinbukan online schedule
+///
+/// Every option can also be set with the environment variable listed next to it, or in the TOML configuration
+/// file under the same name as the long flag. The command line takes precedence over the environment, which
+/// takes precedence over the configuration file.
+#[derive(Debug, clap::Parser)]
+#[command(after_help = "\
+Exit codes:
+  0  the calendar was generated without errors
+  1  some cells could not be parsed, the upstream page format probably changed
+  2  some months could not be fetched, probably a transient network issue
+  3  invalid command line or configuration, including credentials that the host rejected, or the calendar could not \
+be merged or written to --output
+
+When errors of several kinds occur, the highest code is used. With --fail-threshold, errors only lead to a \
+non-zero exit code once there are more of them than the threshold. The calendar is printed in all cases but the last.")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read the configuration from this TOML file
+    #[arg(long, env = "SHINBUKAN_ICS_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Drop events dated before this day, either `today`, `today-Nd` (N days ago) or a YYYY-MM-DD date
+    ///
+    /// Months before the anchor are still fetched, only their events are left out of the output.
+    #[arg(long, env = "SHINBUKAN_ICS_DROP_BEFORE", value_name = "today[-Nd]|YYYY-MM-DD")]
+    drop_before: Option<DateAnchor>,
+
+    /// Set the ORGANIZER of each event, as `Name <mailto:address>`
+    #[arg(long, env = "SHINBUKAN_ICS_ORGANIZER", value_name = "NAME <mailto:ADDRESS>")]
+    organizer: Option<Organizer>,
+
+    /// Set a CONTACT property on each event, e.g. a phone number or address to reach the dojo
+    #[arg(long, env = "SHINBUKAN_ICS_CONTACT")]
+    contact: Option<String>,
+
+    /// Hours below this one are read as afternoon hours [default: 8]
+    #[arg(long, env = "SHINBUKAN_ICS_AFTERNOON_BEFORE", value_name = "HOUR")]
+    afternoon_before: Option<usize>,
+
+    /// Fetch exactly this month, given as YYYY-MM, instead of the rolling window around today; can be repeated
+    #[arg(long = "month", env = "SHINBUKAN_ICS_MONTHS", value_name = "YYYY-MM", value_delimiter = ',')]
+    months: Vec<YearMonth>,
+
+    /// Number of months before the current one to fetch [default: 2]
+    #[arg(long, env = "SHINBUKAN_ICS_MONTHS_BACK", value_name = "N")]
+    months_back: Option<u32>,
+
+    /// Number of months after the current one to fetch [default: 12]
+    #[arg(long, env = "SHINBUKAN_ICS_MONTHS_AHEAD", value_name = "N")]
+    months_ahead: Option<u32>,
+
+    /// Only exit with an error code when more than this many errors occurred; warnings never count [default: 0]
+    #[arg(long, env = "SHINBUKAN_ICS_FAIL_THRESHOLD", value_name = "N")]
+    fail_threshold: Option<usize>,
+
+    /// Venue name that may lead a cell's text, to be moved into the event's LOCATION; can be repeated
+    #[arg(long = "venue", env = "SHINBUKAN_ICS_VENUES", value_name = "NAME", value_delimiter = ',')]
+    venues: Vec<String>,
+
+    /// Add the source page URL and the time it was fetched to the DESCRIPTION of each event
+    #[arg(long, env = "SHINBUKAN_ICS_PROVENANCE")]
+    provenance: bool,
+
+    /// Also give the events with red notes or links an HTML description in X-ALT-DESC, for the clients that show it
+    #[arg(long, env = "SHINBUKAN_ICS_HTML_DESCRIPTION")]
+    html_description: bool,
+
+    /// Tag each event with the month of the page it comes from, as X-SHINBUKAN-SOURCE-MONTH:YYYYMM
+    #[arg(long, env = "SHINBUKAN_ICS_SOURCE_MONTH")]
+    source_month: bool,
+
+    /// SUMMARY of events that only have a time, e.g. 稽古; by default their SUMMARY is left empty with a warning
+    #[arg(long, env = "SHINBUKAN_ICS_EMPTY_SUMMARY", value_name = "TEXT")]
+    empty_summary: Option<String>,
+
+    /// Truncate SUMMARY to this many characters, moving the full text to the DESCRIPTION
+    #[arg(long, env = "SHINBUKAN_ICS_MAX_SUMMARY_LEN", value_name = "N")]
+    max_summary_len: Option<usize>,
+
+    /// Characters decorating time ranges, like bullets or brackets, to strip before parsing them [default: ・()（）【】]
+    #[arg(long, env = "SHINBUKAN_ICS_DECORATIONS", value_name = "CHARS")]
+    decorations: Option<String>,
+
+    /// Characters, like / and ・, separating the groups that share a session, as in `19:00-21:00 少年部/一般部`, to
+    /// give each group its own event at the same time
+    ///
+    /// Lines without a time range are never split. Beware that / also separates venues from their room, as in
+    /// `鳴尾浜 柔道場/自主稽古`.
+    #[arg(long, env = "SHINBUKAN_ICS_SPLIT_SUMMARY_ON", value_name = "CHARS")]
+    split_summary_on: Option<String>,
+
+    /// Debug mode adding an all-day event on the first of each month that had errors, describing them
+    #[arg(long, env = "SHINBUKAN_ICS_INCLUDE_ERRORS_IN_CALENDAR")]
+    include_errors_in_calendar: bool,
+
+    /// Add an all-day event on the first of each month telling how many sessions it has, as an overview
+    #[arg(long, env = "SHINBUKAN_ICS_MONTH_SUMMARY_EVENT")]
+    month_summary_event: bool,
+
+    /// Add the events listed in this file, like national holidays or closures of the dojo, one per line as
+    /// `YYYY-MM-DD [HH:MM-HH:MM] SUMMARY`; lines starting with `#` are comments. Their UIDs are in the `extra.`
+    /// subdomain of --uid-domain, and they have no URL
+    #[arg(long, env = "SHINBUKAN_ICS_EXTRA_EVENTS", value_name = "FILE")]
+    extra_events: Option<PathBuf>,
+
+    /// URL of the calendar pages, with `{year}` and `{month}` placeholders
+    #[arg(long, env = "SHINBUKAN_ICS_URL_TEMPLATE", value_name = "URL")]
+    url_template: Option<String>,
+
+    /// URL of a mirror of the calendar pages, with `{year}` and `{month}` placeholders, used when the primary fails
+    #[arg(long, env = "SHINBUKAN_ICS_FALLBACK_URL_TEMPLATE", value_name = "URL")]
+    fallback_url_template: Option<String>,
+
+    /// Only speak HTTP/1.1 to the hosts of the calendar pages, for hosts that misbehave with HTTP/2
+    ///
+    /// This only matters for HTTPS URLs, as HTTP/2 is never used over plain HTTP.
+    #[arg(long, env = "SHINBUKAN_ICS_HTTP1_ONLY")]
+    http1_only: bool,
+
+    /// Open a new connection for each page, for hosts that drop kept-alive connections without notice
+    #[arg(long, env = "SHINBUKAN_ICS_NO_KEEPALIVE")]
+    no_keepalive: bool,
+
+    /// Close connections that stayed idle for this many seconds, before a host that drops them earlier than the
+    /// default of 90 does
+    #[arg(long, env = "SHINBUKAN_ICS_POOL_IDLE_TIMEOUT_SECS", value_name = "SECONDS", conflicts_with = "no_keepalive")]
+    pool_idle_timeout_secs: Option<u64>,
+
+    /// Number of pages fetched at the same time, the results being put back in order once all are in [default: 16]
+    #[arg(long, env = "SHINBUKAN_ICS_CONCURRENCY", value_name = "N")]
+    concurrency: Option<usize>,
+
+    /// Connect to ADDR for HOST instead of resolving it, keeping the port of the URL, e.g. to fetch from a local server
+    /// while keeping the URL template; can be repeated
+    #[arg(long, env = "SHINBUKAN_ICS_RESOLVE", value_name = "HOST:ADDR", value_delimiter = ',', value_parser = parse_resolve)]
+    resolve: Vec<(String, std::net::IpAddr)>,
+
+    /// Fail to fetch the pages larger than this, instead of reading them whole, to bound the memory used when a host
+    /// misbehaves [default: 4194304]
+    #[arg(long, env = "SHINBUKAN_ICS_MAX_BODY_BYTES", value_name = "BYTES")]
+    max_body_bytes: Option<usize>,
+
+    /// Text of the page served instead of the calendar when the session expired, making the month fail with an
+    /// authentication error instead of parsing errors; can be repeated
+    #[arg(long = "auth-failure-marker", env = "SHINBUKAN_ICS_AUTH_FAILURE_MARKERS", value_name = "TEXT", value_delimiter = ',')]
+    auth_failure_markers: Vec<String>,
+
+    /// Stop at the first month whose page is an authentication failure, without outputting anything
+    #[arg(long, env = "SHINBUKAN_ICS_AUTH_FAIL_FAST")]
+    auth_fail_fast: bool,
+
+    /// Remember the pages in this state file, and reuse the events previously rendered for unchanged months
+    #[arg(long, env = "SHINBUKAN_ICS_SINCE_FILE", value_name = "PATH")]
+    since_file: Option<PathBuf>,
+
+    /// Coordinates of the dojo, as `latitude,longitude`, used by --apple-location
+    #[arg(long, env = "SHINBUKAN_ICS_GEO", value_name = "LAT,LON", allow_hyphen_values = true)]
+    geo: Option<Geo>,
+
+    /// Add X-APPLE-STRUCTURED-LOCATION to events with a LOCATION, so that Apple clients show a map pin at --geo
+    #[arg(long, env = "SHINBUKAN_ICS_APPLE_LOCATION")]
+    apple_location: bool,
+
+    /// Set the COLOR of each event to this CSS3 color name, e.g. `steelblue`
+    #[arg(long, env = "SHINBUKAN_ICS_EVENT_COLOR", value_name = "COLOR")]
+    event_color: Option<Color>,
+
+    /// Set the CATEGORIES and COLOR of events whose SUMMARY contains KEYWORD, overriding --event-color; can be repeated
+    #[arg(long = "category-color", env = "SHINBUKAN_ICS_CATEGORY_COLORS", value_name = "KEYWORD=COLOR", value_delimiter = ',', value_parser = parse_category_color)]
+    category_colors: Vec<(String, Color)>,
+
+    /// Only print the VEVENT blocks, without the VCALENDAR header and footer, to embed them in another calendar
+    #[arg(long, env = "SHINBUKAN_ICS_EVENTS_ONLY")]
+    events_only: bool,
+
+    /// Line ending after the last line of the calendar, for consumers that are picky about it; the other lines end with
+    /// LF [default: crlf]
+    #[arg(long, env = "SHINBUKAN_ICS_FINAL_NEWLINE", value_name = "crlf|none")]
+    final_newline: Option<FinalNewline>,
+
+    /// Timezone in which the calendar's times are written, as an IANA name [default: Asia/Tokyo]
+    #[arg(long, env = "SHINBUKAN_ICS_SOURCE_TZ", value_name = "IANA", value_parser = parse_tz)]
+    source_tz: Option<chrono_tz::Tz>,
+
+    /// Give the times of the events in this timezone, as an IANA name, along with its rules, rather than in UTC
+    ///
+    /// The events take place at the same instants either way, but are then shown in the local time of this zone by
+    /// clients set to another one, e.g. while traveling.
+    #[arg(long, env = "SHINBUKAN_ICS_DISPLAY_TZ", value_name = "IANA", value_parser = parse_tz)]
+    display_tz: Option<chrono_tz::Tz>,
+
+    /// Timezone whose date is today's, for the window of fetched months and the relative dates like --drop-before,
+    /// as an IANA name [default: Asia/Tokyo]
+    ///
+    /// The default follows the dojo's calendar, where the month changes 9 hours before it does in UTC.
+    #[arg(long, env = "SHINBUKAN_ICS_WINDOW_TZ", value_name = "IANA", value_parser = parse_tz)]
+    window_tz: Option<chrono_tz::Tz>,
+
+    /// Write a JSON summary of the run, with the number of events, errors and warnings of each month and whether it
+    /// came from --since-file or the fallback mirror, to this file
+    #[arg(long, env = "SHINBUKAN_ICS_STATUS_FILE", value_name = "PATH")]
+    status_file: Option<PathBuf>,
+
+    /// Fail when fewer events than this are found in total, which probably means that the parser broke [default: 0]
+    #[arg(long, env = "SHINBUKAN_ICS_MIN_EVENTS", value_name = "N")]
+    min_events: Option<usize>,
+
+    /// Fail when fewer events are found than this percentage of the previous run's, as recorded in --since-file
+    ///
+    /// Runs failing this way do not update --since-file, so that the next run compares against the last good count.
+    #[arg(long, env = "SHINBUKAN_ICS_MIN_EVENTS_VS_PREVIOUS", value_name = "PERCENT")]
+    min_events_vs_previous: Option<usize>,
+
+    /// Merge timed events with the same description where one ends exactly when the next starts on the same day
+    #[arg(long, env = "SHINBUKAN_ICS_MERGE_ADJACENT")]
+    merge_adjacent: bool,
+
+    /// Combine the all-day notices of each day, like closures and holidays, into a single all-day event
+    #[arg(long, env = "SHINBUKAN_ICS_MERGE_FULLDAY")]
+    merge_fullday: bool,
+
+    /// Description of the calendar shown by clients, or an empty string for none [default: a note that the
+    /// calendar is generated automatically]
+    #[arg(long, env = "SHINBUKAN_ICS_CAL_DESCRIPTION", value_name = "TEXT")]
+    cal_description: Option<String>,
+
+    /// Name of this deployment, e.g. `prod-a`, put in the PRODID of the calendar instead of the product name and in
+    /// front of the summary lines and in the status file, to tell the outputs of several deployments apart
+    #[arg(long, env = "SHINBUKAN_ICS_INSTANCE_LABEL", value_name = "LABEL")]
+    instance_label: Option<String>,
+
+    /// Domain part of the event UIDs, e.g. `iaido.example`, to keep them unique across deployments [default:
+    /// shinbukan-ics]
+    #[arg(long, alias = "compact-uid-domain", env = "SHINBUKAN_ICS_UID_DOMAIN", value_name = "DOMAIN")]
+    uid_domain: Option<String>,
+
+    /// Leave the URL property, linking to the source page of the month, out of the events
+    #[arg(long, env = "SHINBUKAN_ICS_NO_URL_PROPERTY", conflicts_with = "event_url")]
+    no_url_property: bool,
+
+    /// Only give the events their UID, DTSTAMP, DTSTART, DTEND and SUMMARY, for a smaller calendar, leaving out the URL,
+    /// LOCATION, DESCRIPTION and all the other properties whatever the options adding them
+    #[arg(long, env = "SHINBUKAN_ICS_COMPACT")]
+    compact: bool,
+
+    /// Link the events to this page instead of the source page, with `{year}`, `{month}` and `{day}` placeholders
+    #[arg(long, env = "SHINBUKAN_ICS_EVENT_URL", value_name = "URL")]
+    event_url: Option<String>,
+
+    /// Credentials for the calendar pages, as `user:password`, sent with HTTP basic authentication
+    #[arg(long, env = "SHINBUKAN_ICS_CREDENTIALS", value_name = "USER:PASSWORD", hide_env_values = true)]
+    credentials: Option<Credentials>,
+
+    /// Show all-day events as timed events over these hours, for clients that handle all-day events poorly
+    #[arg(long, env = "SHINBUKAN_ICS_FULLDAY_AS_TIMED", value_name = "HH:MM-HH:MM")]
+    fullday_as_timed: Option<TimeRange>,
+
+    /// Keyword that makes a line an all-day event even if it starts with a time range, replacing the defaults
+    /// of --locale (終日 and 休館, as well as All day and Closed for `en`); can be repeated
+    #[arg(long = "all-day-keyword", env = "SHINBUKAN_ICS_ALL_DAY_KEYWORDS", value_name = "KEYWORD", value_delimiter = ',')]
+    all_day_keywords: Vec<String>,
+
+    /// Leave months without any event or error out of the summary table and --status-file; they are still fetched
+    #[arg(long, env = "SHINBUKAN_ICS_PRUNE_EMPTY")]
+    prune_empty: bool,
+
+    /// Set the DTSTAMP of events to midnight UTC of the day of the run, so that runs on the same day give the same
+    /// output
+    #[arg(long, env = "SHINBUKAN_ICS_PIN_DTSTAMP")]
+    pin_dtstamp: bool,
+
+    /// Drop events more than N days after today, for a lighter calendar of what is coming up
+    ///
+    /// Months past the horizon are still fetched, only their events are left out of the output.
+    #[arg(long, env = "SHINBUKAN_ICS_HORIZON_DAYS", value_name = "N")]
+    horizon_days: Option<u32>,
+
+    /// Warn about timed events at the same place that overlap on the same day, which usually means a typo
+    #[arg(long, env = "SHINBUKAN_ICS_CHECK_OVERLAPS")]
+    check_overlaps: bool,
+
+    /// Check that timed events last at least MINUTES, 5 if not given, as shorter ones like 19:00-19:00 are most likely
+    /// typos
+    #[arg(long, env = "SHINBUKAN_ICS_MIN_INTERVAL_MINS", value_name = "MINUTES", num_args = 0..=1, default_missing_value = "5")]
+    min_interval_mins: Option<usize>,
+
+    /// What to do with the events shorter than --min-interval-mins: keep them with a warning, or leave them out of the
+    /// calendar with a warning [default: warn]
+    #[arg(long, env = "SHINBUKAN_ICS_ON_SHORT_EVENT", value_name = "warn|drop", requires = "min_interval_mins")]
+    on_short_event: Option<ShortEvents>,
+
+    /// Prepend the weekday to the title of each event, written in this language or that of --locale, e.g. `(火) 稽古`
+    /// for `ja`
+    #[arg(long, env = "SHINBUKAN_ICS_WEEKDAY_PREFIX", value_name = "ja|en", num_args = 0..=1)]
+    weekday_prefix: Option<Option<Locale>>,
+
+    /// Run as if the current time was this one, given in RFC 3339 format, e.g. to back-fill or to reproduce a run
+    ///
+    /// This sets the window of fetched months, the relative dates and the DTSTAMP of events.
+    #[arg(long, env = "SHINBUKAN_ICS_NOW", value_name = "RFC3339")]
+    now: Option<DateTime<Utc>>,
+
+    /// Print the parsed form of each month to stderr before rendering it, to debug wrong events
+    #[arg(long, env = "SHINBUKAN_ICS_DUMP_PARSED")]
+    dump_parsed: bool,
+
+    /// Print how each line of the pages was turned into events to stderr: which time ranges were found, which hours
+    /// were taken as afternoon, what made a line an all-day event, and so on
+    #[arg(long, env = "SHINBUKAN_ICS_EXPLAIN")]
+    explain: bool,
+
+    /// Value of the `summary` attribute of the tables holding the schedule, replacing the defaults (日程 and 行事);
+    /// can be repeated
+    #[arg(long = "schedule-summary", env = "SHINBUKAN_ICS_SCHEDULE_SUMMARIES", value_name = "SUMMARY", value_delimiter = ',')]
+    schedule_summaries: Vec<String>,
+
+    /// Also write one complete calendar per month, named `YYYY-MM.ics`, into this directory
+    ///
+    /// Months that could not be fetched keep the file written for them by a previous run, if any.
+    #[arg(long, env = "SHINBUKAN_ICS_SPLIT_OUTPUT", value_name = "DIR")]
+    split_output: Option<PathBuf>,
+
+    /// Write the calendar to this file instead of to stdout, replacing it atomically so that it is never served
+    /// half-written
+    #[arg(long, env = "SHINBUKAN_ICS_OUTPUT", value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Also write the SHA-256 checksum of the calendar to `<FILE>.sha256`, in the format of `sha256sum`, to let
+    /// consumers detect truncated or corrupted downloads; requires --output
+    #[arg(long, env = "SHINBUKAN_ICS_WRITE_CHECKSUM")]
+    write_checksum: bool,
+
+    /// Output this existing calendar, e.g. the same file as --output, with its events from --uid-domain replaced by
+    /// the generated ones, keeping the events added by hand and its other components and properties
+    ///
+    /// A missing file counts as an empty calendar. Nothing gets output if the file cannot be read.
+    #[arg(long, env = "SHINBUKAN_ICS_MERGE_INTO", value_name = "EXISTING.ics")]
+    merge_into: Option<PathBuf>,
+
+    /// Append the local times of timed events, e.g. `(JST 19:00-21:00)`, to their title, to check the conversion
+    /// to UTC at a glance
+    #[arg(long, env = "SHINBUKAN_ICS_TIMEZONE_OUTPUT_DISPLAY")]
+    timezone_output_display: bool,
+
+    /// Refuse to fetch months before this year, as they would come from a typo [default: 2000]
+    #[arg(long, env = "SHINBUKAN_ICS_MIN_YEAR", value_name = "YEAR")]
+    min_year: Option<usize>,
+
+    /// Refuse to fetch months after this year, as they would come from a typo [default: 2100]
+    #[arg(long, env = "SHINBUKAN_ICS_MAX_YEAR", value_name = "YEAR")]
+    max_year: Option<usize>,
+
+    /// Output one event per schedule entry (`ics`), the same as jCal JSON (`jcal`), or a single VFREEBUSY with the
+    /// periods they take (`freebusy`) [default: ics]
+    #[arg(long, env = "SHINBUKAN_ICS_FORMAT", value_name = "ics|jcal|freebusy")]
+    format: Option<OutputFormat>,
+
+    /// Count all-day events as busy for the whole day with --format freebusy, instead of leaving them out
+    #[arg(long, env = "SHINBUKAN_ICS_FREEBUSY_FULL_DAY")]
+    freebusy_full_day: bool,
+
+    /// Do not warn about pages that are not valid EUC-JP, whose invalid bytes show up as `�` in events
+    #[arg(long, env = "SHINBUKAN_ICS_TOLERATE_ENCODING_ERRORS", conflicts_with = "strict")]
+    tolerate_encoding_errors: bool,
+
+    /// Treat pages that are not valid EUC-JP as errors rather than warnings
+    #[arg(long, env = "SHINBUKAN_ICS_STRICT")]
+    strict: bool,
+
+    /// What to do when several events get the same UID, which clients would show as a single event: suffix the UIDs
+    /// of all but the first, fail the run, or only warn
+    #[arg(long, env = "SHINBUKAN_ICS_ON_UID_COLLISION", value_name = "suffix|error|ignore")]
+    on_uid_collision: Option<UidCollision>,
+
+    /// Order of the events in the calendar: by start time, grouped by category then by start time, or by summary
+    /// then by start time [default: date]
+    #[arg(long, env = "SHINBUKAN_ICS_SORT_BY", value_name = "date|category|summary")]
+    sort_by: Option<SortBy>,
+
+    /// Make the events that repeat with the same time and contents on other days, like a seminar held on scattered
+    /// dates, into a single event with an RDATE for each other day
+    #[arg(long, env = "SHINBUKAN_ICS_RDATE_REPEATS")]
+    rdate_repeats: bool,
+
+    /// Language setting the defaults of the options that depend on one, like --all-day-keyword and
+    /// --weekday-prefix [default: ja]
+    #[arg(long, env = "SHINBUKAN_ICS_LOCALE", value_name = "ja|en")]
+    locale: Option<Locale>,
 }
 
-#[derive(Debug, Hash)]
-enum Event {
-    Timed {
-        day: usize,
-        from: Time,
-        to: Time,
-        text: String,
-    },
-    FullDay {
-        day: usize,
-        text: String,
-    }
+fn parse_tz(s: &str) -> anyhow::Result<chrono_tz::Tz> {
+    s.parse().map_err(|_| anyhow::anyhow!("Unknown timezone {s:?}, expected an IANA name like Asia/Tokyo"))
 }
 
-impl Event {
-    fn append(&mut self, append: &str) {
-        match self {
-            Event::Timed { text, .. } => {
-                text.push(' ');
-                text.push_str(append);
-            }
-            Event::FullDay { text, .. } => {
-                text.push(' ');
-                text.push_str(append);
-            }
-        }
-    }
+fn parse_category_color(s: &str) -> anyhow::Result<(String, Color)> {
+    let (keyword, color) = s.split_once('=').ok_or_else(|| anyhow::anyhow!("Expected KEYWORD=COLOR, got {s:?}"))?;
+    Ok((keyword.to_owned(), color.parse()?))
+}
 
-    fn as_ics(&self, year: usize, month: usize) -> String {
-        let mut hasher = std::hash::DefaultHasher::new();
-        self.hash(&mut hasher);
-        let hash = hasher.finish();
-        let (start, end, text) = match self {
-            Event::FullDay { day, text } => {
-                let day = format!("DATE:{year:04}{month:02}{day:02}");
-                (format!("DTSTART;VALUE={day}"), format!("DTEND;VALUE={day}"), text)
-            }
-            Event::Timed { day, from, to, text } => {
-                let year = year.try_into().unwrap();
-                let month = month.try_into().unwrap();
-                let day = (*day).try_into().unwrap();
-                let from_hours = from.hours.try_into().unwrap();
-                let to_hours = to.hours.try_into().unwrap();
-                let from_mins = from.minutes.try_into().unwrap();
-                let to_mins = to.minutes.try_into().unwrap();
-                let from = chrono_tz::Asia::Tokyo.with_ymd_and_hms(year, month, day, from_hours, from_mins, 0).unwrap().with_timezone(&Utc).format("DTSTART:%Y%m%dT%H%M%SZ");
-                let to = chrono_tz::Asia::Tokyo.with_ymd_and_hms(year, month, day, to_hours, to_mins, 0).unwrap().with_timezone(&Utc).format("DTEND:%Y%m%dT%H%M%SZ");
-                (format!("{from}"), format!("{to}"), text)
-            }
-        };
-        #[cfg(not(test))]
-        let now = Utc::now().format("%Y%m%dT%H%M%SZ");
-        #[cfg(test)]
-        let now = "20000101T000000Z";
-
-        format!(
-            "BEGIN:VEVENT\n\
-             UID:{hash}@shinbukan-ics\n\
-             DTSTAMP:{now}\n\
-             {start}\n\
-             {end}\n\
-             SUMMARY:{text}\n\
-             URL:http://brionac.s17.xrea.com/schedule/homepage/homepage/calendar/{year}/{year}{month:02}.html\n\
-             END:VEVENT\n"
-        )
-    }
+fn parse_resolve(s: &str) -> anyhow::Result<(String, std::net::IpAddr)> {
+    // Addresses may be IPv6 ones, with colons of their own
+    let (host, addr) = s.split_once(':').ok_or_else(|| anyhow::anyhow!("Expected HOST:ADDR, got {s:?}"))?;
+    let addr = addr.parse().map_err(|_| anyhow::anyhow!("Expected an IP address after the host, got {addr:?}"))?;
+    Ok((host.to_owned(), addr))
 }
 
-#[derive(Debug)]
-struct MonthResult {
-    year: usize,
-    month: usize,
-    events: Vec<Event>,
-    errors: Vec<anyhow::Error>,
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Print the shell completion script for the given shell
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Print the configuration resulting from the file, the environment and the command line, without fetching
+    /// anything; the password of --credentials is redacted
+    Config {
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+    /// Insert, update and delete events in a Google calendar to match the schedule, instead of printing it
+    ///
+    /// Events are matched by UID, and only the events created by this command are ever updated or deleted. Events
+    /// are only deleted from months that were parsed without errors.
+    PushGoogle {
+        /// Identifier of the calendar, as shown in its settings
+        #[arg(long, env = "SHINBUKAN_ICS_GOOGLE_CALENDAR_ID")]
+        calendar_id: String,
+        /// OAuth access token allowed to edit the events of the calendar
+        #[arg(long, env = "SHINBUKAN_ICS_GOOGLE_ACCESS_TOKEN", hide_env_values = true)]
+        access_token: String,
+        /// Only print the operations that would be made on the calendar
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Parse the pages saved in a directory, e.g. the test fixtures, and report which ones have errors, without
+    /// fetching anything nor printing the calendar
+    ///
+    /// Pages are named after their month, as in `2024-06.html` or `2024-06-description.html`. A TOML configuration
+    /// with the same name as a page replaces the configuration for it. Exits with 1 if any page has errors.
+    ParseOnly {
+        dir: PathBuf,
+        /// Only parse the cells of this day, without reporting the other days as missing, to debug a single cell;
+        /// can be repeated
+        #[arg(long = "only-day", value_name = "N")]
+        only_days: Vec<usize>,
+    },
 }
 
-impl MonthResult {
-    fn new(year: usize, month: usize) -> MonthResult {
-        MonthResult {
-            year,
-            month,
-            events: Vec::new(),
-            errors: Vec::new(),
-        }
-    }
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
 
-    fn event(&mut self, day: usize, mut from: Time, mut to: Time, text: &str) {
-        // Hours are set in 12 am/pm format, but without the am/pm indication
-        if from.hours < 8 {
-            from.hours += 12;
+impl Args {
+    fn into_config(self) -> anyhow::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file_with_locale(path, self.locale)?,
+            None => Config::for_locale(self.locale.unwrap_or(Locale::Ja)),
+        };
+        if let Some(drop_before) = self.drop_before {
+            config.drop_before = Some(drop_before);
         }
-        if to.hours < 8 {
-            to.hours += 12;
+        if let Some(organizer) = self.organizer {
+            config.organizer = Some(organizer);
         }
-        self.events.push(Event::Timed { day, from, to, text: text.to_owned() })
-    }
-
-    fn full_day_event(&mut self, day: usize, text: &str) {
-        self.events.push(Event::FullDay { day, text: text.to_owned() })
-    }
-
-    fn append_to_last_event(&mut self, text: &str) {
-        self.events.last_mut().unwrap().append(text);
+        if let Some(contact) = self.contact {
+            config.contact = Some(contact);
+        }
+        if let Some(afternoon_before) = self.afternoon_before {
+            config.afternoon_before = afternoon_before;
+        }
+        if !self.months.is_empty() {
+            config.months = self.months;
+        }
+        if let Some(months_back) = self.months_back {
+            config.months_back = months_back;
+        }
+        if let Some(months_ahead) = self.months_ahead {
+            config.months_ahead = months_ahead;
+        }
+        if let Some(fail_threshold) = self.fail_threshold {
+            config.fail_threshold = fail_threshold;
+        }
+        if !self.venues.is_empty() {
+            config.venues = self.venues;
+        }
+        config.provenance |= self.provenance;
+        config.html_description |= self.html_description;
+        config.source_month |= self.source_month;
+        if let Some(empty_summary) = self.empty_summary {
+            config.empty_summary = Some(empty_summary);
+        }
+        if let Some(max_summary_len) = self.max_summary_len {
+            config.max_summary_len = Some(max_summary_len);
+        }
+        if let Some(decorations) = self.decorations {
+            config.decorations = decorations;
+        }
+        if let Some(split_summary_on) = self.split_summary_on {
+            config.split_summary_on = split_summary_on;
+        }
+        config.include_errors_in_calendar |= self.include_errors_in_calendar;
+        config.month_summary_event |= self.month_summary_event;
+        if let Some(extra_events) = self.extra_events {
+            config.extra_events = ExtraEvent::load(&extra_events)?;
+        }
+        if let Some(url_template) = self.url_template {
+            config.url_template = url_template;
+        }
+        if let Some(fallback_url_template) = self.fallback_url_template {
+            config.fallback_url_template = Some(fallback_url_template);
+        }
+        config.http1_only |= self.http1_only;
+        config.no_keepalive |= self.no_keepalive;
+        if let Some(pool_idle_timeout_secs) = self.pool_idle_timeout_secs {
+            config.pool_idle_timeout_secs = Some(pool_idle_timeout_secs);
+        }
+        if let Some(concurrency) = self.concurrency {
+            config.concurrency = concurrency;
+        }
+        if !self.resolve.is_empty() {
+            config.resolve = self.resolve.into_iter().collect();
+        }
+        if let Some(max_body_bytes) = self.max_body_bytes {
+            config.max_body_bytes = max_body_bytes;
+        }
+        if !self.auth_failure_markers.is_empty() {
+            config.auth_failure_markers = self.auth_failure_markers;
+        }
+        config.auth_fail_fast |= self.auth_fail_fast;
+        if let Some(since_file) = self.since_file {
+            config.since_file = Some(since_file);
+        }
+        if let Some(geo) = self.geo {
+            config.geo = Some(geo);
+        }
+        config.apple_location |= self.apple_location;
+        if let Some(event_color) = self.event_color {
+            config.event_color = Some(event_color);
+        }
+        if !self.category_colors.is_empty() {
+            config.category_colors = self.category_colors.into_iter().collect();
+        }
+        config.events_only |= self.events_only;
+        if let Some(final_newline) = self.final_newline {
+            config.final_newline = final_newline;
+        }
+        if let Some(source_tz) = self.source_tz {
+            config.source_tz = source_tz;
+        }
+        if let Some(display_tz) = self.display_tz {
+            config.display_tz = Some(display_tz);
+        }
+        if let Some(window_tz) = self.window_tz {
+            config.window_tz = window_tz;
+        }
+        if let Some(status_file) = self.status_file {
+            config.status_file = Some(status_file);
+        }
+        if let Some(min_events) = self.min_events {
+            config.min_events = min_events;
+        }
+        if let Some(min_events_vs_previous) = self.min_events_vs_previous {
+            config.min_events_vs_previous = Some(min_events_vs_previous);
+        }
+        config.merge_adjacent |= self.merge_adjacent;
+        config.merge_fullday |= self.merge_fullday;
+        if let Some(cal_description) = self.cal_description {
+            config.cal_description = cal_description;
+        }
+        if let Some(instance_label) = self.instance_label {
+            config.instance_label = instance_label;
+        }
+        if let Some(uid_domain) = self.uid_domain {
+            config.uid_domain = uid_domain;
+        }
+        config.no_url_property |= self.no_url_property;
+        config.compact |= self.compact;
+        if let Some(event_url) = self.event_url {
+            config.event_url = Some(event_url);
+        }
+        if let Some(credentials) = self.credentials {
+            config.credentials = Some(credentials);
+        }
+        if let Some(fullday_as_timed) = self.fullday_as_timed {
+            config.fullday_as_timed = Some(fullday_as_timed);
+        }
+        if !self.all_day_keywords.is_empty() {
+            config.all_day_keywords = self.all_day_keywords;
+        }
+        config.prune_empty |= self.prune_empty;
+        config.pin_dtstamp |= self.pin_dtstamp;
+        if let Some(horizon_days) = self.horizon_days {
+            config.horizon_days = Some(horizon_days);
+        }
+        config.check_overlaps |= self.check_overlaps;
+        if let Some(min_interval_mins) = self.min_interval_mins {
+            config.min_interval_mins = Some(min_interval_mins);
+        }
+        if let Some(on_short_event) = self.on_short_event {
+            config.on_short_event = on_short_event;
+        }
+        if let Some(weekday_prefix) = self.weekday_prefix {
+            config.weekday_prefix = Some(weekday_prefix.unwrap_or(config.locale));
+        }
+        if let Some(now) = self.now {
+            config.now = now;
+        }
+        config.dump_parsed |= self.dump_parsed;
+        config.explain |= self.explain;
+        if !self.schedule_summaries.is_empty() {
+            config.schedule_summaries = self.schedule_summaries;
+        }
+        if let Some(split_output) = self.split_output {
+            config.split_output = Some(split_output);
+        }
+        if let Some(output) = self.output {
+            config.output = Some(output);
+        }
+        config.write_checksum |= self.write_checksum;
+        if let Some(merge_into) = self.merge_into {
+            config.merge_into = Some(merge_into);
+        }
+        config.timezone_output_display |= self.timezone_output_display;
+        if let Some(min_year) = self.min_year {
+            config.min_year = min_year;
+        }
+        if let Some(max_year) = self.max_year {
+            config.max_year = max_year;
+        }
+        if let Some(format) = self.format {
+            config.format = format;
+        }
+        config.freebusy_full_day |= self.freebusy_full_day;
+        config.tolerate_encoding_errors |= self.tolerate_encoding_errors;
+        config.strict |= self.strict;
+        if let Some(on_uid_collision) = self.on_uid_collision {
+            config.on_uid_collision = on_uid_collision;
+        }
+        if let Some(sort_by) = self.sort_by {
+            config.sort_by = sort_by;
+        }
+        config.rdate_repeats |= self.rdate_repeats;
+        if config.merge_into.is_some() && config.format == OutputFormat::Jcal {
+            anyhow::bail!("--merge-into only works with ICS calendars, and not with the jcal format");
+        }
+        if config.write_checksum && config.output.is_none() {
+            anyhow::bail!("--write-checksum only works with --output, next to which the checksum file is written");
+        }
+        Ok(config)
     }
+}
 
-    fn error(&mut self, err: anyhow::Error) {
-        self.errors.push(err);
-    }
+const EXIT_PARSE: u8 = 1;
+const EXIT_FETCH: u8 = 2;
+const EXIT_CONFIG: u8 = 3;
 
-    fn days_in_month(&self) -> usize {
-        let first_day = NaiveDate::from_ymd_opt(self.year.try_into().unwrap(), self.month.try_into().unwrap(), 1).unwrap();
-        let next_month = first_day + Months::new(1);
-        let interval = next_month - first_day;
-        interval.num_days().try_into().unwrap()
+/// Report the errors of a month the way the operator expects them, returning the matching exit code
+fn report_errors(res: &MonthResult) -> (u8, usize) {
+    let mut exit_code = 0;
+    for e in res.errors() {
+        eprintln!("---");
+        eprintln!("Error occurred while processing the online calendar!");
+        eprintln!("{e}");
+        eprintln!("---");
+        let code = match e.kind() {
+            ErrorKind::Parse => EXIT_PARSE,
+            ErrorKind::Fetch => EXIT_FETCH,
+            ErrorKind::Credentials => EXIT_CONFIG,
+        };
+        exit_code = exit_code.max(code);
     }
+    (exit_code, res.errors().len())
+}
 
-    fn events_as_ics(&self) -> String {
-        let mut res = String::new();
-        for e in &self.events {
-            res.push_str(&e.as_ics(self.year, self.month));
+/// The errors of the run as a whole about colliding UIDs, warning about those that `on_uid_collision` lets through
+fn uid_collision_errors(results: &[MonthResult], config: &Config) -> Vec<Error> {
+    let mut errors = Vec::new();
+    for (uid, count) in uid_collisions(results, config) {
+        match config.on_uid_collision {
+            UidCollision::Suffix => eprintln!("Warning: the UID {uid} is used by {count} events, suffixed for all but the first"),
+            UidCollision::Error => errors.push(Error::UidCollision { uid, count }),
+            UidCollision::Ignore => eprintln!("Warning: the UID {uid} is used by {count} events"),
         }
-        res
     }
+    errors
+}
 
-    fn errors(&self) -> &[anyhow::Error] {
-        &self.errors
+/// Report the errors of the run as a whole, which fail it no matter `fail_threshold`, returning the exit code
+fn report_run_errors(run_errors: &[Error]) -> u8 {
+    for e in run_errors {
+        eprintln!("---");
+        eprintln!("Error occurred while processing the online calendar!");
+        eprintln!("{e}");
+        eprintln!("---");
+    }
+    match run_errors.is_empty() {
+        true => 0,
+        false => EXIT_PARSE,
     }
 }
 
-async fn fetch_calendar_for(year: usize, month: usize) -> anyhow::Result<String> {
-    let url = format!("http://brionac.s17.xrea.com/schedule/homepage/homepage/calendar/{year}/{year}{month:02}.html");
-    tracing::debug!(%url, "fetching calendar page");
-    let resp = reqwest::get(url).await?;
-    let bytes = resp.bytes().await?;
-    let text = encoding_rs::EUC_JP.decode(&bytes).0.into_owned();
-    Ok(text)
+/// Fetch and parse the months, or report the first authentication failure and return `None` with `auth_fail_fast`
+async fn handle_months(fetcher: &HttpFetcher, months: Vec<YearMonth>, config: &Config, state: Option<&State>) -> Option<Vec<MonthResult>> {
+    let results = shinbukan_ics::handle_months(fetcher, &months, config, state).await;
+    let failed = results.iter().find(|r| r.errors().iter().any(|e| matches!(e, Error::AuthFailure { .. })));
+    if let Some(failed) = failed.filter(|_| config.auth_fail_fast) {
+        report_errors(failed);
+        eprintln!("Stopping at {:04}-{:02}, as the other months would most likely fail to authenticate too", failed.year(), failed.month());
+        return None;
+    }
+    Some(results)
 }
 
-fn parse_calendar(res: &mut MonthResult, cal: &str) {
-    let doc = scraper::Html::parse_document(cal);
-    let selector = scraper::Selector::parse(r#"table[summary="日程"] td"#).unwrap();
-    let mut parsed_days = vec![false; res.days_in_month()];
-    for element in doc.select(&selector) {
-        if let Some(day) = parse_cell(&mut *res, &element) {
-            if !parsed_days[day - 1] {
-                parsed_days[day - 1] = true;
-            } else {
-                res.error(anyhow!("Parsed day {day} twice"));
-            }
+async fn push_google(fetcher: &HttpFetcher, months: Vec<YearMonth>, config: &Config, calendar: &GoogleCalendar, dry_run: bool) -> ExitCode {
+    let Some(results) = handle_months(fetcher, months, config, None).await else {
+        return ExitCode::from(EXIT_CONFIG);
+    };
+    let mut exit_code = 0;
+    let mut error_count = 0;
+    for res in &results {
+        let (code, count) = report_errors(res);
+        exit_code = exit_code.max(code);
+        error_count += count;
+    }
+    let existing = match calendar.managed_events().await {
+        Ok(existing) => existing,
+        Err(e) => {
+            eprintln!("Failed to list the events of the Google calendar: {e:#}");
+            return ExitCode::from(EXIT_FETCH);
         }
+    };
+    let mut run_errors = Vec::from_iter(check_event_count(&results, config, None));
+    run_errors.extend(uid_collision_errors(&results, config));
+    let mut ops = google::plan(&results, config, existing);
+    if !run_errors.is_empty() {
+        // A schedule that was most likely misread must not wipe the events from the calendar
+        ops.retain(|op| !matches!(op, google::Operation::Delete { .. }));
     }
-    for day in 0..parsed_days.len() {
-        if !parsed_days[day] {
-            res.error(anyhow!("Did not parse day {}", day + 1));
+    for op in ops {
+        if dry_run {
+            println!("{op}");
+        } else if let Err(e) = calendar.apply(&op).await {
+            eprintln!("{e:#}");
+            exit_code = exit_code.max(EXIT_FETCH);
+        } else {
+            tracing::info!("{op}");
         }
     }
-}
-
-fn get_day_number(elt: &Node) -> Option<usize> {
-    let Node::Text(txt) = elt else {
-        return None;
-    };
-    Some(txt.trim().parse().unwrap())
-}
-
-fn parse_time(time: &str) -> Time {
-    match time.split_once(':') {
-        None => Time { hours: time.parse().unwrap(), minutes: 0 },
-        Some((hours, minutes)) => Time { hours: hours.parse().unwrap(), minutes: minutes.parse().unwrap() },
+    eprint!("{}", summary_table(&results, config));
+    if error_count <= config.fail_threshold && exit_code < EXIT_FETCH {
+        exit_code = 0;
     }
+    exit_code = exit_code.max(report_run_errors(&run_errors));
+    ExitCode::from(exit_code)
 }
 
-// Returns the number of the parsed day, if applicable
-fn parse_cell(res: &mut MonthResult, cell: &scraper::ElementRef<'_>) -> Option<usize> {
-    let mut children = cell.children();
-    let Some(day_num_elt) = children.next() else {
-        return None;
-    };
-    let Some(day_num) = get_day_number(day_num_elt.value()) else {
-        return None;
+fn parse_only(dir: &Path, config: &Config) -> ExitCode {
+    let pages = std::fs::read_dir(dir).and_then(|entries| entries.map(|e| Ok(e?.path())).collect::<std::io::Result<Vec<_>>>());
+    let mut pages = match pages {
+        Ok(pages) => pages.into_iter().filter(|p| p.extension().is_some_and(|e| e == "html")).collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Failed to list the pages in {dir:?}: {e}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
     };
-    while let Some(c) = children.next() {
-        match c.value() {
-            Node::Element(elt) => match elt.name() {
-                "br" => continue,
-                "font" if elt.attr("size") == Some("-1") => continue,
-                "font" if elt.attr("color") == Some("red") => {
-                    for n in c.descendants() {
-                        if let Node::Text(txt) = n.value() {
-                            res.append_to_last_event(txt);
-                        }
-                    }
+    pages.sort();
+    let mut failed = 0;
+    for page in &pages {
+        let name = page.file_name().unwrap_or_default().to_string_lossy();
+        match parse_file(page, config) {
+            Ok(res) if res.errors().is_empty() => println!("ok   {name}: {} events, {} warnings", res.event_count(), res.warnings().len()),
+            Ok(res) => {
+                failed += 1;
+                println!("FAIL {name}: {} errors", res.errors().len());
+                for e in res.errors() {
+                    println!("     {e}");
                 }
-                _ => res.error(anyhow!("Encountered unexpected element while parsing day {day_num}: {elt:?}")),
             }
-            Node::Text(txt) => {
-                let txt = txt.trim();
-                if txt.is_empty() {
-                    continue;
-                }
-                match txt.split_once(' ') {
-                    None => res.full_day_event(day_num, txt),
-                    Some((time, rem)) => match time.split_once(&['-', '~']) {
-                        None => res.full_day_event(day_num, txt),
-                        Some((from, to)) => res.event(day_num, parse_time(from), parse_time(to), rem),
-                    }
-                }
+            Err(e) => {
+                failed += 1;
+                println!("FAIL {name}: {e:#}");
             }
-            _ => res.error(anyhow!("Encountered unexpected node while parsing day {day_num}: {:?}", c.value())),
         }
     }
-    Some(day_num)
+    println!("{} pages, {failed} failed", pages.len());
+    ExitCode::from(if failed > 0 { EXIT_PARSE } else { 0 })
 }
 
-async fn handle_month(year: usize, month: usize) -> MonthResult {
-    let mut result = MonthResult::new(year, month);
-    let cal = match fetch_calendar_for(year, month).await {
-        Ok(cal) => cal,
-        Err(err) => {
-            result.error(err);
-            return result;
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = match <Args as clap::Parser>::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            // --help and --version are reported as errors too, but go to stdout
+            return if e.use_stderr() { ExitCode::from(EXIT_CONFIG) } else { ExitCode::SUCCESS };
         }
     };
-    parse_calendar(&mut result, &cal);
-    result
-}
-
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+    let command = args.command.take();
+    if let Some(Command::Completions { shell }) = command {
+        clap_complete::generate(shell, &mut Args::command(), env!("CARGO_BIN_NAME"), &mut std::io::stdout());
+        return ExitCode::SUCCESS;
+    }
+    let config = match args.into_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    if let Some(Command::Config { format }) = command {
+        match format {
+            ConfigFormat::Toml => print!("{}", config.to_toml()),
+            ConfigFormat::Json => println!("{}", config.to_json()),
+        }
+        return ExitCode::SUCCESS;
+    }
     tracing_subscriber::fmt::init();
+    if let Some(Command::ParseOnly { dir, only_days }) = command {
+        return parse_only(&dir, &Config { only_days, ..config });
+    }
 
-    let today = Utc::now().naive_utc().date();
-    let first_date = today - Months::new(2);
+    let months = match months_to_fetch(&config) {
+        Ok(months) => months,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    let fetcher = HttpFetcher::new(&config);
+    if let Some(Command::PushGoogle { calendar_id, access_token, dry_run }) = command {
+        let calendar = GoogleCalendar::new(&calendar_id, &access_token);
+        return push_google(&fetcher, months, &config, &calendar, dry_run).await;
+    }
+    let state = match config.since_file.as_deref().map(State::load).transpose() {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
 
     // Parse the calendar
-    let results = futures::stream::iter(0..NUM_MONTHS)
-        .map(|add_months| {
-            let for_date = first_date + Months::new(add_months);
-            let for_year = for_date.year().try_into().unwrap();
-            let for_month = for_date.month().try_into().unwrap();
-            handle_month(for_year, for_month)
-        })
-        .buffered(16)
-        .collect::<Vec<MonthResult>>()
-        .await;
+    let Some(results) = handle_months(&fetcher, months, &config, state.as_ref()).await else {
+        return ExitCode::from(EXIT_CONFIG);
+    };
+
+    if config.dump_parsed {
+        for res in &results {
+            eprintln!("{res:#?}");
+        }
+    }
+    for explanation in results.iter().flat_map(MonthResult::explanations) {
+        eprint!("{explanation}");
+    }
 
     // Generate the ICS file
-    println!("BEGIN:VCALENDAR");
-    println!("VERSION:2.0");
-    println!("PRODID:-//Shinbukan-ICS//Shinbukan-ICS//");
-    println!("NAME:Shinbukan");
-    println!("X-WR-CALNAME:Shinbukan");
-    let mut had_errors = false;
-    for res in results {
-        print!("{}", res.events_as_ics());
-        if !res.errors().is_empty() {
-            for e in res.errors() {
-                eprintln!("---");
-                eprintln!("Error occurred while processing the online calendar!");
-                eprintln!("{e:?}");
-                eprintln!("---");
+    let mut calendar = render_calendar(&results, &config);
+    let mut output_failed = false;
+    if let Some(existing) = &config.merge_into {
+        match merge_into_file(existing, &calendar, &config) {
+            Ok(merged) => calendar = merged,
+            Err(e) => {
+                // Outputting the calendar alone would drop the events added by hand
+                eprintln!("Failed to merge into the existing calendar, not outputting anything: {e:#}");
+                output_failed = true;
             }
-            had_errors = true;
         }
     }
-    println!("END:VCALENDAR");
-
-    if !had_errors {
-        Ok(())
-    } else {
-        Err(anyhow!("Errors occurred while processing the input"))
+    match &config.output {
+        _ if output_failed => (),
+        Some(path) => {
+            if let Err(e) = write_calendar(path, &calendar, config.write_checksum) {
+                eprintln!("Failed to write the calendar: {e:#}");
+                output_failed = true;
+            }
+        }
+        None => print!("{calendar}"),
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn calendar_fixtures() {
-        insta::glob!("fixtures/*.html", |path| {
-            // Retrieve year/month from filename
-            let filename = path.file_name().unwrap().to_str().unwrap();
-            let yearmonth = filename.split_once('.').unwrap().0;
-            let (year, month) = yearmonth.split_once('-').unwrap();
-            let year = year.parse().unwrap();
-            let month = month.parse().unwrap();
-            let mut result = MonthResult::new(year, month);
-
-            // Read file and parse calendar
-            let input = std::fs::read_to_string(path).unwrap();
-            parse_calendar(&mut result, &input);
-
-            // Assert the snapshot
-            insta::assert_debug_snapshot!(result);
-
-            // Generate the relevant ICS file
-            insta::assert_snapshot!(result.events_as_ics());
-        })
+    if let Some(dir) = &config.split_output {
+        let written = std::fs::create_dir_all(dir).with_context(|| format!("Creating {dir:?}")).and_then(|()| {
+            render_split(&results, &config).into_iter().try_for_each(|(name, ics)| write_atomically(&dir.join(name), ics.as_bytes()))
+        });
+        if let Err(e) = written {
+            eprintln!("Failed to write the per-month calendars to {dir:?}: {e:#}");
+            output_failed = true;
+        }
     }
+    let count_error = check_event_count(&results, &config, state.as_ref());
+    if let Some(path) = config.since_file.as_ref().filter(|_| count_error.is_none()) {
+        if let Err(e) = State::from_results(&results, &config).save(path) {
+            eprintln!("Failed to save the state for the next run: {e:#}");
+        }
+    }
+    let mut run_errors = Vec::from_iter(count_error);
+    run_errors.extend(uid_collision_errors(&results, &config));
+    if let Some(path) = &config.status_file {
+        if let Err(e) = write_atomically(path, Status::new(&results, &run_errors, &config).to_json().as_bytes()) {
+            eprintln!("Failed to write the status file {path:?}: {e:#}");
+        }
+    }
+    let mut exit_code = 0;
+    let mut error_count = 0;
+    for res in &results {
+        for w in res.warnings() {
+            eprintln!("Warning for {:04}-{:02}: {w}", res.year(), res.month());
+        }
+        let (code, count) = report_errors(res);
+        exit_code = exit_code.max(code);
+        error_count += count;
+    }
+    eprint!("{}", summary_table(&results, &config));
+    if error_count <= config.fail_threshold {
+        exit_code = 0;
+    }
+    // Too few events means that the parser broke without noticing, no matter the threshold
+    exit_code = exit_code.max(report_run_errors(&run_errors));
+    if output_failed {
+        exit_code = exit_code.max(EXIT_CONFIG);
+    }
+    ExitCode::from(exit_code)
 }
+
@@ -0,0 +1,221 @@
+//! Writing of the calendar to a file, for publishing pipelines that serve it as is
+
+use crate::{Config, FinalNewline};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Write the calendar to `path`, along with its SHA-256 checksum in `<path>.sha256` if `checksum` is set
+///
+/// Both files are replaced atomically, so that readers never see them half-written. The checksum file is in the
+/// format of `sha256sum`, so that `sha256sum -c` can check it.
+pub fn write_calendar(path: &Path, calendar: &str, checksum: bool) -> anyhow::Result<()> {
+    write_atomically(path, calendar.as_bytes())?;
+    if checksum {
+        let digest = Sha256::digest(calendar.as_bytes()).iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        write_atomically(&checksum_path(path), format!("{digest}  {name}\n").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Path of the checksum of the calendar written to `path`
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// Merge the generated calendar into the one in the file at `path`, as per `merge_into`
+///
+/// A missing file is taken as an empty calendar, for the first run.
+pub fn merge_into_file(path: &Path, calendar: &str, config: &Config) -> anyhow::Result<String> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Reading calendar {path:?}")),
+    };
+    Ok(merge_into(&existing, calendar, config))
+}
+
+/// Replace the components of the `existing` calendar that were generated by this tool, as told by the domain of their
+/// UID or its subdomain for the `extra_events`, with those of `calendar`
+///
+/// The properties of the existing calendar, like its name, and its other components are kept as they are, up to the
+/// line endings. The generated components come after them.
+pub fn merge_into(existing: &str, calendar: &str, config: &Config) -> String {
+    let domain = config.uid_domain.trim_start_matches('@');
+    let suffixes = [format!("@{domain}"), format!("@{}.{domain}", crate::EXTRA_EVENTS_SUBDOMAIN)];
+    let existing = Calendar::parse(existing);
+    let generated = Calendar::parse(calendar);
+    let properties = match existing.properties.is_empty() {
+        true => &generated.properties,
+        false => &existing.properties,
+    };
+    // Timezones are told apart by their TZID rather than their UID, and those generated again replace the existing ones
+    let tzids = generated.components.iter().filter_map(|c| tzid(c)).collect::<Vec<_>>();
+    let foreign = existing
+        .components
+        .iter()
+        .filter(|c| !uid(c).is_some_and(|uid| suffixes.iter().any(|suffix| uid.ends_with(suffix.as_str()))))
+        .filter(|c| !tzid(c).is_some_and(|tzid| tzids.contains(&tzid)));
+    let mut res = String::new();
+    if !config.events_only {
+        res.push_str("BEGIN:VCALENDAR\n");
+    }
+    for line in properties.iter().copied().chain(foreign.chain(&generated.components).flatten().copied()) {
+        res.push_str(line);
+        res.push('\n');
+    }
+    if !config.events_only {
+        res.push_str("END:VCALENDAR\n");
+    }
+    end_with(res, config.final_newline)
+}
+
+/// Replace the line ending after the last line of a calendar, whose lines otherwise all end with LF
+pub(crate) fn end_with(mut calendar: String, final_newline: FinalNewline) -> String {
+    if calendar.ends_with('\n') {
+        calendar.pop();
+        if final_newline == FinalNewline::Crlf {
+            calendar.push_str("\r\n");
+        }
+    }
+    calendar
+}
+
+/// Fold the lines longer than 75 octets as per RFC 5545 §3.1, each continuation line starting with a space
+///
+/// Lines are only cut between characters, so that the Japanese text, 3 octets per character, stays valid UTF-8.
+pub(crate) fn fold_lines(calendar: &str) -> String {
+    let mut res = String::with_capacity(calendar.len());
+    for line in calendar.split_inclusive('\n') {
+        let mut octets = 0;
+        for c in line.chars() {
+            if c != '\n' && octets + c.len_utf8() > 75 {
+                res.push_str("\n ");
+                octets = 1;
+            }
+            octets += c.len_utf8();
+            res.push(c);
+        }
+    }
+    res
+}
+
+/// The lines of an ICS calendar, split into its own properties and its components
+pub(crate) struct Calendar<'a> {
+    pub(crate) properties: Vec<&'a str>,
+    pub(crate) components: Vec<Vec<&'a str>>,
+}
+
+impl Calendar<'_> {
+    /// Split a calendar, that may also be a bare list of components as output with `events_only`
+    pub(crate) fn parse(ics: &str) -> Calendar<'_> {
+        let mut res = Calendar { properties: Vec::new(), components: Vec::new() };
+        // Nesting level within the current top-level component, which may have sub-components like VALARM
+        let mut depth = 0;
+        for line in ics.lines().filter(|l| !l.is_empty()) {
+            let upper = line.to_ascii_uppercase();
+            if upper == "BEGIN:VCALENDAR" || upper == "END:VCALENDAR" {
+                continue;
+            }
+            if depth == 0 && upper.starts_with("BEGIN:") {
+                res.components.push(Vec::new());
+            }
+            if depth == 0 && !upper.starts_with("BEGIN:") {
+                res.properties.push(line);
+                continue;
+            }
+            if upper.starts_with("BEGIN:") {
+                depth += 1;
+            } else if upper.starts_with("END:") {
+                depth -= 1;
+            }
+            res.components.last_mut().unwrap().push(line);
+        }
+        res
+    }
+}
+
+/// UID of a component, unfolding it if it was folded over several lines
+fn uid(component: &[&str]) -> Option<String> {
+    let start = component.iter().position(|l| l.get(..4).is_some_and(|p| p.eq_ignore_ascii_case("UID:")))?;
+    let mut uid = component[start][4..].to_owned();
+    for continued in component[start + 1..].iter().map_while(|l| l.strip_prefix([' ', '\t'])) {
+        uid.push_str(continued);
+    }
+    Some(uid)
+}
+
+/// TZID of a VTIMEZONE component
+fn tzid<'a>(component: &[&'a str]) -> Option<&'a str> {
+    if !component.first()?.eq_ignore_ascii_case("BEGIN:VTIMEZONE") {
+        return None;
+    }
+    component.iter().find_map(|l| l.get(..5).filter(|p| p.eq_ignore_ascii_case("TZID:")).map(|_| &l[5..]))
+}
+
+/// Write to a temporary file next to `path` then rename it, as renames within a filesystem are atomic
+///
+/// The temporary file is named after the process and a counter, so that concurrent writers never share it.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}-{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, contents).with_context(|| format!("Writing {tmp:?}"))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Renaming {tmp:?} to {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_matches() {
+        let dir = std::env::temp_dir().join(format!("shinbukan-ics-output-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("shinbukan.ics");
+        write_calendar(&path, "BEGIN:VCALENDAR\nEND:VCALENDAR\n", true).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        let checksum = std::fs::read_to_string(checksum_path(&path)).unwrap();
+        let leftovers = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(written, b"BEGIN:VCALENDAR\nEND:VCALENDAR\n");
+        // As given by `sha256sum` for these contents
+        assert_eq!(checksum, "be46d5a463390b4537813cac084ae608c121650f383bc72707d32a37241905d1  shinbukan.ics\n");
+        // The temporary files were renamed into place
+        assert_eq!(leftovers, 2);
+    }
+
+    #[test]
+    fn merge() {
+        let existing = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nX-WR-CALNAME:Dojo\r\n\
+            BEGIN:VEVENT\r\nUID:old@shinbukan-ics\r\nSUMMARY:稽古\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:party@example.org\r\nSUMMARY:忘年会\r\nBEGIN:VALARM\r\nACTION:DISPLAY\r\nEND:VALARM\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:folded@shinbu\r\n kan-ics\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nUID:holiday@extra.shinbukan-ics\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let generated = "BEGIN:VCALENDAR\nVERSION:2.0\nX-WR-CALNAME:Shinbukan\nBEGIN:VEVENT\nUID:new@shinbukan-ics\nEND:VEVENT\nEND:VCALENDAR\n";
+        assert_eq!(
+            merge_into(existing, generated, &Config::default()),
+            "BEGIN:VCALENDAR\nVERSION:2.0\nX-WR-CALNAME:Dojo\n\
+             BEGIN:VEVENT\nUID:party@example.org\nSUMMARY:忘年会\nBEGIN:VALARM\nACTION:DISPLAY\nEND:VALARM\nEND:VEVENT\n\
+             BEGIN:VEVENT\nUID:new@shinbukan-ics\nEND:VEVENT\n\
+             END:VCALENDAR\r\n",
+        );
+        // The first run starts from the generated calendar, only without its last newline
+        let none = Config { final_newline: FinalNewline::Omit, ..Config::default() };
+        assert_eq!(merge_into("", generated, &none), generated.trim_end());
+        // Timezones are replaced by those generated again
+        let timezone = "BEGIN:VTIMEZONE\nTZID:Europe/Paris\nEND:VTIMEZONE\n";
+        let merged = merge_into(&format!("{timezone}BEGIN:VTIMEZONE\nTZID:Asia/Tokyo\nEND:VTIMEZONE\n"), timezone, &none);
+        assert_eq!(merged, format!("BEGIN:VCALENDAR\nBEGIN:VTIMEZONE\nTZID:Asia/Tokyo\nEND:VTIMEZONE\n{timezone}END:VCALENDAR"));
+        // Another domain makes all the existing events foreign
+        let config = Config { uid_domain: String::from("iaido.example"), ..Config::default() };
+        assert_eq!(merge_into(existing, generated, &config).matches("BEGIN:VEVENT").count(), 5);
+    }
+}
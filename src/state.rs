@@ -0,0 +1,141 @@
+//! State kept between runs of the incremental mode, to avoid re-rendering months whose page did not change
+
+use crate::output::{write_atomically, Calendar};
+use crate::{Config, MonthResult};
+use anyhow::Context;
+use chrono::DateTime;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+/// What was rendered for each month during the previous run, along with the hash of what it was rendered from
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct State {
+    /// Keyed by `YYYY-MM`
+    months: BTreeMap<String, MonthState>,
+    /// Number of events found across all months
+    #[serde(default)]
+    total_events: Option<usize>,
+    /// DTSTAMP of each event by UID, keyed by `YYYY-MM`, to keep it as it was while the event does not change
+    #[serde(default)]
+    dtstamps: BTreeMap<String, BTreeMap<String, Stamp>>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct MonthState {
+    source_hash: u64,
+    ics: String,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub(crate) struct Stamp {
+    /// Hash of the rendered event, leaving out its DTSTAMP
+    pub(crate) hash: u64,
+    pub(crate) dtstamp: String,
+}
+
+impl State {
+    /// Load the state from a file, a missing file meaning that this is the first run
+    pub fn load(path: &Path) -> anyhow::Result<State> {
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let json = std::fs::read_to_string(path).with_context(|| format!("Reading state file {path:?}"))?;
+        serde_json::from_str(&json).with_context(|| format!("Parsing state file {path:?}"))
+    }
+
+    /// Save the state to a file, replacing it atomically so that an interrupted run does not lose the previous one
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        write_atomically(path, json.as_bytes()).with_context(|| format!("Writing state file {path:?}"))
+    }
+
+    /// Number of events the previous run found
+    pub fn total_events(&self) -> Option<usize> {
+        self.total_events
+    }
+
+    /// The ICS previously rendered for this month, if it was rendered from the same source
+    pub(crate) fn rendered(&self, year: usize, month: usize, source_hash: u64) -> Option<&str> {
+        self.months
+            .get(&key(year, month))
+            .filter(|m| m.source_hash == source_hash)
+            .map(|m| &*m.ics)
+    }
+
+    /// The DTSTAMPs previously given to the events of this month, by UID
+    pub(crate) fn dtstamps(&self, year: usize, month: usize) -> BTreeMap<String, Stamp> {
+        self.dtstamps.get(&key(year, month)).cloned().unwrap_or_default()
+    }
+
+    /// Build the state to save after this run, out of its results
+    ///
+    /// Months that had errors or warnings are left out, so that they get looked at again on the next run.
+    pub fn from_results(results: &[MonthResult], config: &Config) -> State {
+        let months = results
+            .iter()
+            .filter(|r| r.errors().is_empty() && r.warnings().is_empty())
+            .filter_map(|r| {
+                let state = MonthState {
+                    source_hash: r.source_hash?,
+                    ics: r.events_as_ics(config),
+                };
+                Some((key(r.year(), r.month()), state))
+            })
+            .collect();
+        // Unlike the renderings, DTSTAMPs are worth keeping for the months with warnings too
+        let dtstamps = results
+            .iter()
+            .map(|r| (key(r.year(), r.month()), stamps(&r.events_as_ics(config))))
+            .filter(|(_, stamps)| !stamps.is_empty())
+            .collect();
+        State {
+            months,
+            total_events: Some(results.iter().map(MonthResult::event_count).sum()),
+            dtstamps,
+        }
+    }
+}
+
+fn key(year: usize, month: usize) -> String {
+    format!("{year:04}-{month:02}")
+}
+
+/// The DTSTAMP of each of the rendered events, by UID
+fn stamps(events: &str) -> BTreeMap<String, Stamp> {
+    Calendar::parse(events)
+        .components
+        .iter()
+        .filter_map(|c| {
+            let uid = c.iter().find_map(|l| l.strip_prefix("UID:"))?;
+            let dtstamp = c.iter().find_map(|l| l.strip_prefix("DTSTAMP:"))?;
+            Some((uid.to_owned(), Stamp { hash: event_hash(c), dtstamp: dtstamp.to_owned() }))
+        })
+        .collect()
+}
+
+/// Hash of the lines of a rendered event, leaving out its DTSTAMP which changes on every run
+pub(crate) fn event_hash(lines: &[&str]) -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    for line in lines.iter().filter(|l| !l.starts_with("DTSTAMP:")) {
+        line.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Hash of everything the rendering of a month depends on: its page, and the configuration
+pub(crate) fn source_hash(page: &str, config: &Config) -> u64 {
+    let mut hasher = std::hash::DefaultHasher::new();
+    page.hash(&mut hasher);
+    // The run time only matters through relative dates and pinned DTSTAMPs, that only change from one day to the next
+    let mut config = config.clone();
+    // Explaining the parsing does not change its result
+    config.explain = false;
+    if config.drop_before.is_none() && config.horizon_days.is_none() && !config.pin_dtstamp {
+        config.now = DateTime::UNIX_EPOCH;
+    } else {
+        config.now = config.today().and_hms_opt(0, 0, 0).unwrap().and_utc();
+    }
+    format!("{config:?}").hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,176 @@
+//! Machine-readable summary of a run, for monitoring jobs to alert on failures or on drops in the number of events
+
+use crate::{Config, Error, ErrorKind, MonthResult};
+use chrono::SecondsFormat;
+
+/// Version of the JSON schema, to be bumped whenever a field changes meaning or goes away
+const STATUS_VERSION: u32 = 1;
+
+#[derive(Debug, serde::Serialize)]
+pub struct Status {
+    version: u32,
+    /// Time of the run, in RFC 3339 format
+    run_at: String,
+    /// Label of the deployment, as per `instance_label`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    months: Vec<MonthStatus>,
+    /// Errors about the run as a whole rather than about a month
+    errors: Vec<String>,
+    /// Whether the run is considered successful, as per `fail_threshold`
+    ok: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MonthStatus {
+    year: usize,
+    month: usize,
+    events: usize,
+    errors: usize,
+    warnings: usize,
+    /// Whether the events were reused from `since_file` or served by the fallback mirror, rather than fresh
+    served_stale: bool,
+}
+
+impl Status {
+    pub fn new(results: &[MonthResult], run_errors: &[Error], config: &Config) -> Status {
+        let months = reported(results, config)
+            .map(|r| MonthStatus {
+                year: r.year(),
+                month: r.month(),
+                events: r.event_count(),
+                errors: r.errors().len(),
+                warnings: r.warnings().len(),
+                served_stale: r.served_stale(),
+            })
+            .collect::<Vec<_>>();
+        let error_count = months.iter().map(|m| m.errors).sum::<usize>();
+        Status {
+            version: STATUS_VERSION,
+            run_at: config.now.to_rfc3339_opts(SecondsFormat::Secs, true),
+            instance: Some(config.instance_label.clone()).filter(|l| !l.is_empty()),
+            months,
+            errors: run_errors.iter().map(Error::to_string).collect(),
+            ok: error_count <= config.fail_threshold && run_errors.is_empty(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// One line per month telling at a glance whether it went fine, for the operator reading the logs
+pub fn summary_table(results: &[MonthResult], config: &Config) -> String {
+    let mut table = String::new();
+    for r in reported(results, config) {
+        let status = if r.errors().is_empty() { "OK" } else { "FAILED" };
+        let mut line = format!("{:04}-{:02}  {status:<6}  {:>3} events", r.year(), r.month(), r.event_count());
+        for (kind, name) in [(ErrorKind::Fetch, "fetch"), (ErrorKind::Credentials, "credentials"), (ErrorKind::Parse, "parse")] {
+            match r.errors().iter().filter(|e| e.kind() == kind).count() {
+                0 => (),
+                1 => line.push_str(&format!(", 1 {name} error")),
+                n => line.push_str(&format!(", {n} {name} errors")),
+            }
+        }
+        match r.warnings().len() {
+            0 => (),
+            1 => line.push_str(", 1 warning"),
+            n => line.push_str(&format!(", {n} warnings")),
+        }
+        if !config.instance_label.is_empty() {
+            table.push_str(&format!("{}  ", config.instance_label));
+        }
+        table.push_str(&line);
+        table.push('\n');
+    }
+    table
+}
+
+/// The months to report on, leaving out those without events or errors when `prune_empty` is set
+fn reported<'a>(results: &'a [MonthResult], config: &'a Config) -> impl Iterator<Item = &'a MonthResult> {
+    results.iter().filter(|r| !config.prune_empty || r.event_count() > 0 || !r.errors().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_calendar;
+
+    #[test]
+    fn status() {
+        let config = Config { now: "2024-06-10T12:00:00Z".parse().unwrap(), ..Config::default() };
+        let mut june = MonthResult::new(2024, 6);
+        parse_calendar(&mut june, include_str!("fixtures/2024-06.html"), &config);
+        let mut july = MonthResult::new(2024, 7);
+        july.error(Error::Fetch(anyhow::anyhow!("connection refused")));
+
+        let status: serde_json::Value = serde_json::from_str(&Status::new(&[june, july], &[], &config).to_json()).unwrap();
+        assert_eq!(
+            status,
+            serde_json::json!({
+                "version": 1,
+                "run_at": "2024-06-10T12:00:00Z",
+                "months": [
+                    { "year": 2024, "month": 6, "events": 13, "errors": 0, "warnings": 0, "served_stale": false },
+                    { "year": 2024, "month": 7, "events": 0, "errors": 1, "warnings": 0, "served_stale": false },
+                ],
+                "errors": [],
+                "ok": false,
+            }),
+        );
+    }
+
+    #[test]
+    fn table() {
+        let mut june = MonthResult::new(2024, 6);
+        parse_calendar(&mut june, include_str!("fixtures/2024-06.html"), &Config::default());
+        let mut july = MonthResult::new(2024, 7);
+        july.error(Error::Fetch(anyhow::anyhow!("connection refused")));
+        let mut august = MonthResult::new(2024, 8);
+        parse_calendar(&mut august, include_str!("fixtures/2024-08-bare-time.html"), &Config::default());
+        august.error(Error::MissingDay { day: 3 });
+        august.error(Error::MissingDay { day: 4 });
+        let mut september = MonthResult::new(2024, 9);
+        september.error(Error::AuthFailure { marker: String::from("ログイン") });
+
+        assert_eq!(
+            summary_table(&[june, july, august, september], &Config::default()),
+            "2024-06  OK       13 events\n\
+             2024-07  FAILED    0 events, 1 fetch error\n\
+             2024-08  FAILED    1 events, 2 parse errors, 1 warning\n\
+             2024-09  FAILED    0 events, 1 credentials error\n",
+        );
+    }
+
+    #[test]
+    fn prune_empty() {
+        let config = Config { prune_empty: true, ..Config::default() };
+        let mut june = MonthResult::new(2024, 6);
+        parse_calendar(&mut june, include_str!("fixtures/2024-06.html"), &config);
+        let mut july = MonthResult::new(2024, 7);
+        july.error(Error::Fetch(anyhow::anyhow!("connection refused")));
+        let mut may = MonthResult::new(2025, 5);
+        parse_calendar(&mut may, include_str!("fixtures/2025-05-blank.html"), &config);
+        let results = [june, july, may];
+
+        assert_eq!(
+            summary_table(&results, &config),
+            "2024-06  OK       13 events\n\
+             2024-07  FAILED    0 events, 1 fetch error\n",
+        );
+        let status: serde_json::Value = serde_json::from_str(&Status::new(&results, &[], &config).to_json()).unwrap();
+        assert_eq!(status["months"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn instance_label() {
+        let config = Config { instance_label: String::from("prod-a"), ..Config::default() };
+        let mut june = MonthResult::new(2024, 6);
+        parse_calendar(&mut june, include_str!("fixtures/2024-06.html"), &config);
+        let results = [june];
+        assert_eq!(summary_table(&results, &config), "prod-a  2024-06  OK       13 events\n");
+        let status: serde_json::Value = serde_json::from_str(&Status::new(&results, &[], &config).to_json()).unwrap();
+        assert_eq!(status["instance"], "prod-a");
+    }
+}
@@ -0,0 +1,83 @@
+//! VTIMEZONE components, for the calendars whose events are given in the local time of a zone
+
+use chrono::{DateTime, Duration, Offset, TimeZone, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+
+/// The VTIMEZONE of `tz` from `from` to `to`, with the observance in effect at `from` then one per change of offset
+///
+/// The changes are listed one by one rather than as recurrence rules, as chrono-tz only tells the offsets themselves.
+pub(crate) fn vtimezone(tz: Tz, from: DateTime<Utc>, to: DateTime<Utc>) -> String {
+    let offset = |t: DateTime<Utc>| tz.offset_from_utc_datetime(&t.naive_utc());
+    let mut res = format!("BEGIN:VTIMEZONE\nTZID:{}\n", tz.name());
+    let mut current = offset(from);
+    res.push_str(&observance(from, current, current));
+    let mut day = from;
+    while day < to {
+        let next = day + Duration::days(1);
+        if offset(next) != current {
+            // Narrow the change down to the second, with `after` always past it
+            let (mut before, mut after) = (day, next);
+            while after - before > Duration::seconds(1) {
+                let middle = before + (after - before) / 2;
+                match offset(middle) == current {
+                    true => before = middle,
+                    false => after = middle,
+                }
+            }
+            let new = offset(after);
+            res.push_str(&observance(after, current, new));
+            current = new;
+        }
+        day = next;
+    }
+    res.push_str("END:VTIMEZONE\n");
+    res
+}
+
+/// An observance starting at `start`, whose DTSTART is written in the local time from before it
+fn observance(start: DateTime<Utc>, before: <Tz as TimeZone>::Offset, after: <Tz as TimeZone>::Offset) -> String {
+    let kind = match after.dst_offset().is_zero() {
+        true => "STANDARD",
+        false => "DAYLIGHT",
+    };
+    format!(
+        "BEGIN:{kind}\n\
+         DTSTART:{}\n\
+         TZOFFSETFROM:{}\n\
+         TZOFFSETTO:{}\n\
+         TZNAME:{}\n\
+         END:{kind}\n",
+        start.with_timezone(&before.fix()).format("%Y%m%dT%H%M%S"),
+        utc_offset(before),
+        utc_offset(after),
+        after.abbreviation(),
+    )
+}
+
+/// An offset in the `+HHMM` format of iCalendar
+fn utc_offset(offset: <Tz as TimeZone>::Offset) -> String {
+    let seconds = offset.fix().local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    format!("{sign}{:02}{:02}", seconds.abs() / 3600, seconds.abs() % 3600 / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let (from, to) = ("2024-03-01T00:00:00Z".parse().unwrap(), "2024-11-01T00:00:00Z".parse().unwrap());
+        assert_eq!(
+            vtimezone(chrono_tz::Europe::Paris, from, to),
+            "BEGIN:VTIMEZONE\nTZID:Europe/Paris\n\
+             BEGIN:STANDARD\nDTSTART:20240301T010000\nTZOFFSETFROM:+0100\nTZOFFSETTO:+0100\nTZNAME:CET\nEND:STANDARD\n\
+             BEGIN:DAYLIGHT\nDTSTART:20240331T020000\nTZOFFSETFROM:+0100\nTZOFFSETTO:+0200\nTZNAME:CEST\nEND:DAYLIGHT\n\
+             BEGIN:STANDARD\nDTSTART:20241027T030000\nTZOFFSETFROM:+0200\nTZOFFSETTO:+0100\nTZNAME:CET\nEND:STANDARD\n\
+             END:VTIMEZONE\n",
+        );
+        // Negative offsets, and zones without DST
+        assert!(vtimezone(chrono_tz::America::New_York, from, to).contains("\nTZOFFSETFROM:-0500\nTZOFFSETTO:-0400\nTZNAME:EDT\n"));
+        assert_eq!(vtimezone(chrono_tz::Asia::Tokyo, from, to).matches("BEGIN:STANDARD").count(), 1);
+    }
+}
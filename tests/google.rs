@@ -0,0 +1,83 @@
+//! Tests of the Google Calendar client, against a local server standing in for the API
+
+use shinbukan_ics::google::{plan, GoogleCalendar, Operation};
+use shinbukan_ics::{parse_calendar, Config, MonthResult};
+use wiremock::matchers::{body_partial_json, header, method, path, query_param, query_param_is_missing};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const EVENTS: &str = "/calendars/dojo%23shared@group.calendar.google.com/events";
+
+#[tokio::test]
+async fn push_pages_and_marks_events() {
+    let server = MockServer::start().await;
+    let calendar = GoogleCalendar::with_api_url(&server.uri(), "dojo#shared@group.calendar.google.com", "token");
+    let mut june = MonthResult::new(2024, 6);
+    parse_calendar(&mut june, include_str!("../src/fixtures/2024-06.html"), &Config::default());
+    let ops = plan(std::slice::from_ref(&june), &Config::default(), vec![]);
+    let Operation::Insert(first) = &ops[0] else {
+        panic!("Expected an insertion, got {}", ops[0]);
+    };
+
+    // A stale event on the first page, an unchanged one on the second
+    let stale = serde_json::json!({
+        "id": "stale",
+        "iCalUID": "stale@shinbukan-ics",
+        "summary": "稽古",
+        "start": { "dateTime": "2024-06-05T19:00:00+09:00", "timeZone": "Asia/Tokyo" },
+        "end": { "dateTime": "2024-06-05T21:00:00+09:00", "timeZone": "Asia/Tokyo" },
+    });
+    let mut unchanged = serde_json::to_value(first).unwrap();
+    unchanged["id"] = "unchanged".into();
+    Mock::given(method("GET"))
+        .and(path(EVENTS))
+        .and(header("authorization", "Bearer token"))
+        .and(query_param("privateExtendedProperty", "shinbukan-ics=1"))
+        .and(query_param_is_missing("pageToken"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "items": [stale], "nextPageToken": "2" })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(EVENTS))
+        .and(query_param("pageToken", "2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "items": [unchanged] })))
+        .mount(&server)
+        .await;
+
+    let existing = calendar.managed_events().await.unwrap();
+    assert_eq!(existing.iter().map(|(id, _)| id.as_str()).collect::<Vec<_>>(), ["stale", "unchanged"]);
+    let ops = plan(std::slice::from_ref(&june), &Config::default(), existing);
+    assert_eq!(ops.len(), 13);
+    assert!(!ops.iter().any(|op| matches!(op, Operation::Insert(e) if e == first)));
+    assert_eq!(ops.last().unwrap().to_string(), "delete stale@shinbukan-ics");
+
+    Mock::given(method("POST"))
+        .and(path(format!("{EVENTS}/import")))
+        .and(header("authorization", "Bearer token"))
+        .and(body_partial_json(serde_json::json!({ "extendedProperties": { "private": { "shinbukan-ics": "1" } } })))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(12)
+        .mount(&server)
+        .await;
+    Mock::given(method("DELETE"))
+        .and(path(format!("{EVENTS}/stale")))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    for op in &ops {
+        calendar.apply(op).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn api_errors_are_reported() {
+    let server = MockServer::start().await;
+    let calendar = GoogleCalendar::with_api_url(&server.uri(), "primary", "expired");
+    Mock::given(method("GET"))
+        .and(path("/calendars/primary/events"))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&server)
+        .await;
+    let err = calendar.managed_events().await.unwrap_err();
+    assert!(format!("{err:#}").contains("401"), "{err:#}");
+}
@@ -0,0 +1,250 @@
+//! End-to-end tests of the HTTP fetcher, against a local server serving the fixtures like the dojo's host does
+
+use shinbukan_ics::{handle_month, render_calendar, Config, Credentials, DirFetcher, ErrorKind, HttpFetcher, MonthResult};
+use wiremock::matchers::{basic_auth, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures");
+
+/// Contents of a fixture, re-encoded to EUC-JP like the upstream pages
+fn euc_jp_fixture(name: &str) -> Vec<u8> {
+    let page = std::fs::read_to_string(format!("{FIXTURES}/{name}")).unwrap();
+    encoding_rs::EUC_JP.encode(&page).0.into_owned()
+}
+
+/// A local server serving every `YYYY-MM.html` fixture under `/calendar/{year}/{year}{month}.html`
+struct FixtureServer {
+    server: MockServer,
+}
+
+impl FixtureServer {
+    async fn start() -> FixtureServer {
+        let server = MockServer::start().await;
+        for entry in std::fs::read_dir(FIXTURES).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            // Fixtures with a `-description` suffix are variants of a real month, which the host would not serve
+            let Some((year, month)) = name.strip_suffix(".html").and_then(|n| n.split_once('-')) else {
+                continue;
+            };
+            if month.contains('-') {
+                continue;
+            }
+            Mock::given(method("GET"))
+                .and(path(format!("/calendar/{year}/{year}{month}.html")))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(euc_jp_fixture(&name)))
+                .mount(&server)
+                .await;
+        }
+        FixtureServer { server }
+    }
+
+    /// Configuration fetching from this server
+    fn config(&self) -> Config {
+        Config {
+            url_template: self.url_template("calendar"),
+            ..Config::default()
+        }
+    }
+
+    fn url_template(&self, prefix: &str) -> String {
+        format!("{}/{prefix}/{{year}}/{{year}}{{month}}.html", self.server.uri())
+    }
+
+    async fn handle_month(&self, year: usize, month: usize, config: &Config) -> MonthResult {
+        handle_month(&HttpFetcher::new(config), year, month, config).await
+    }
+}
+
+#[tokio::test]
+async fn http_matches_dir_fetcher() {
+    let server = FixtureServer::start().await;
+    let config = server.config();
+    let dir = DirFetcher::new(FIXTURES);
+    for (year, month) in [(2024, 12), (2025, 1)] {
+        let over_http = server.handle_month(year, month, &config).await;
+        assert!(over_http.errors().is_empty(), "{:?}", over_http.errors());
+        let from_dir = handle_month(&dir, year, month, &config).await;
+        // The DTSTAMP of both runs may differ by a second, and is not what is being tested here
+        let strip = |r: MonthResult| {
+            render_calendar(&[r], &config).lines().filter(|l| !l.starts_with("DTSTAMP:")).collect::<Vec<_>>().join("\n")
+        };
+        assert_eq!(strip(over_http), strip(from_dir));
+    }
+}
+
+#[tokio::test]
+async fn connection_tuning() {
+    let server = FixtureServer::start().await;
+    let config = Config { http1_only: true, no_keepalive: true, pool_idle_timeout_secs: Some(1), ..server.config() };
+    let fetcher = HttpFetcher::new(&config);
+    for (year, month) in [(2024, 12), (2025, 1)] {
+        let result = handle_month(&fetcher, year, month, &config).await;
+        assert!(result.errors().is_empty(), "{:?}", result.errors());
+        assert!(result.event_count() > 0);
+    }
+}
+
+#[tokio::test]
+async fn resolve_overrides_the_host() {
+    let server = FixtureServer::start().await;
+    let port = server.server.address().port();
+    let config = Config {
+        url_template: format!("http://brionac.s17.xrea.com:{port}/calendar/{{year}}/{{year}}{{month}}.html"),
+        resolve: [(String::from("brionac.s17.xrea.com"), std::net::Ipv4Addr::LOCALHOST.into())].into(),
+        ..Config::default()
+    };
+    let result = server.handle_month(2024, 12, &config).await;
+    assert!(result.errors().is_empty(), "{:?}", result.errors());
+    assert!(result.event_count() > 0);
+}
+
+#[tokio::test]
+async fn missing_page_is_a_fetch_error() {
+    let server = FixtureServer::start().await;
+    let config = server.config();
+    let result = server.handle_month(2030, 1, &config).await;
+    assert_eq!(result.errors().len(), 1);
+    assert!(result.errors()[0].to_string().contains("404"), "{}", result.errors()[0]);
+}
+
+#[tokio::test]
+async fn invalid_euc_jp_is_reported() {
+    let server = FixtureServer::start().await;
+    // Cut the first multibyte character of the first event, and slip in a byte that is never valid in EUC-JP
+    let mut page = euc_jp_fixture("2024-06.html");
+    let needle = encoding_rs::EUC_JP.encode("鳴尾浜").0.into_owned();
+    let at = page.windows(needle.len()).position(|w| w == needle).unwrap();
+    page[at] = 0xff;
+    Mock::given(method("GET"))
+        .and(path("/corrupted/2024/202406.html"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(page))
+        .mount(&server.server)
+        .await;
+    let config = Config { url_template: server.url_template("corrupted"), ..Config::default() };
+
+    let result = server.handle_month(2024, 6, &config).await;
+    assert!(result.errors().is_empty(), "{:?}", result.errors());
+    assert_eq!(result.warnings().len(), 1);
+    assert_eq!(
+        result.warnings()[0].to_string(),
+        r#"The page is not valid EUC-JP, with 3 invalid characters starting on line 47, near "2<br> 1-5 �槌�� 柔道場/自""#,
+    );
+
+    let strict = Config { strict: true, ..config.clone() };
+    let result = server.handle_month(2024, 6, &strict).await;
+    assert_eq!(result.errors().len(), 1);
+    assert_eq!(result.errors()[0].kind(), ErrorKind::Fetch);
+
+    let tolerant = Config { tolerate_encoding_errors: true, ..config };
+    let result = server.handle_month(2024, 6, &tolerant).await;
+    assert!(result.errors().is_empty() && result.warnings().is_empty());
+}
+
+#[tokio::test]
+async fn oversized_page_is_a_fetch_error() {
+    let server = FixtureServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/huge/2024/202406.html"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![b'a'; 5 * 1024 * 1024]))
+        .mount(&server.server)
+        .await;
+    let config = Config { url_template: server.url_template("huge"), ..Config::default() };
+    let result = server.handle_month(2024, 6, &config).await;
+    assert_eq!(result.errors().len(), 1);
+    assert_eq!(result.errors()[0].kind(), ErrorKind::Fetch);
+    assert!(result.errors()[0].to_string().contains("larger than the maximum of 4194304 bytes"), "{}", result.errors()[0]);
+
+    // A low limit refuses the real pages too
+    let config = Config { max_body_bytes: 1000, ..server.config() };
+    let result = server.handle_month(2024, 6, &config).await;
+    assert_eq!(result.errors().len(), 1);
+    assert_eq!(result.errors()[0].kind(), ErrorKind::Fetch);
+}
+
+#[tokio::test]
+async fn fallback_serves_when_primary_fails() {
+    let server = FixtureServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/primary/2024/202406.html"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&server.server)
+        .await;
+
+    let config = Config {
+        url_template: server.url_template("primary"),
+        fallback_url_template: Some(server.url_template("calendar")),
+        ..Config::default()
+    };
+    let result = server.handle_month(2024, 6, &config).await;
+    assert!(result.errors().is_empty(), "{:?}", result.errors());
+    assert!(result.events_as_ics(&config).contains("SUMMARY:鳴尾浜 柔道場/自主稽古"));
+    // The mirror may lag behind, which the status file tells
+    assert!(result.served_stale());
+}
+
+#[tokio::test]
+async fn truncated_primary_falls_back() {
+    let server = FixtureServer::start().await;
+    let mut page = euc_jp_fixture("2024-06.html");
+    page.truncate(page.len() / 2);
+    Mock::given(method("GET"))
+        .and(path("/truncated/2024/202406.html"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(page))
+        .expect(2)
+        .mount(&server.server)
+        .await;
+
+    let fallback = Config {
+        url_template: server.url_template("truncated"),
+        fallback_url_template: Some(server.url_template("calendar")),
+        ..Config::default()
+    };
+    let result = server.handle_month(2024, 6, &fallback).await;
+    assert!(result.errors().is_empty(), "{:?}", result.errors());
+    assert!(result.events_as_ics(&fallback).contains("SUMMARY:鳴尾浜 柔道場/自主稽古"));
+
+    let config = Config { url_template: server.url_template("truncated"), ..Config::default() };
+    let result = server.handle_month(2024, 6, &config).await;
+    assert_eq!(result.errors().len(), 1);
+    assert_eq!(result.errors()[0].kind(), ErrorKind::Fetch);
+    assert!(result.errors()[0].to_string().contains("before its closing </html>"), "{}", result.errors()[0]);
+}
+
+#[tokio::test]
+async fn primary_failure_without_fallback_is_an_error() {
+    let server = FixtureServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/primary/2024/202406.html"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server.server)
+        .await;
+
+    let config = Config {
+        url_template: server.url_template("primary"),
+        ..Config::default()
+    };
+    let result = server.handle_month(2024, 6, &config).await;
+    assert_eq!(result.errors().len(), 1);
+    assert!(result.errors()[0].to_string().contains("500"), "{}", result.errors()[0]);
+}
+
+#[tokio::test]
+async fn credentials_with_special_characters() {
+    let server = FixtureServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/private/2024/202406.html"))
+        .and(basic_auth("dojo", "p@ss/w:rd%"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(euc_jp_fixture("2024-06.html")))
+        .mount(&server.server)
+        .await;
+
+    let config = Config {
+        url_template: server.url_template("private"),
+        credentials: Some("dojo:p@ss/w:rd%".parse::<Credentials>().unwrap()),
+        ..Config::default()
+    };
+    let result = server.handle_month(2024, 6, &config).await;
+    assert!(result.errors().is_empty(), "{:?}", result.errors());
+    assert_eq!(result.event_count(), 13);
+}
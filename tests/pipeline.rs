@@ -0,0 +1,152 @@
+use shinbukan_ics::{check_event_count, handle_month, handle_month_incremental, handle_months, render_calendar, CalendarFetcher, Config, DirFetcher, State, YearMonth};
+use std::collections::HashMap;
+
+/// DTSTAMP is the time of the run, which cannot be pinned from outside the crate
+fn without_dtstamp(ics: &str) -> String {
+    ics.lines()
+        .map(|l| if l.starts_with("DTSTAMP:") { "DTSTAMP:[now]" } else { l })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[tokio::test]
+async fn fetch_parse_render() {
+    let fetcher = DirFetcher::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures"));
+    let config = Config::default();
+    let mut results = Vec::new();
+    for (year, month) in [(2024, 12), (2025, 1)] {
+        let result = handle_month(&fetcher, year, month, &config).await;
+        assert!(result.errors().is_empty(), "{:?}", result.errors());
+        results.push(result);
+    }
+    insta::assert_snapshot!(without_dtstamp(&render_calendar(&results, &config)));
+}
+
+#[tokio::test]
+async fn dir_fetcher_reads_euc_jp() {
+    let dir = std::env::temp_dir().join(format!("shinbukan-ics-euc-jp-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let page = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/2024-12.html")).unwrap();
+    std::fs::write(dir.join("2024-12.html"), encoding_rs::EUC_JP.encode(&page).0).unwrap();
+    let config = Config::default();
+    let result = handle_month(&DirFetcher::new(&dir), 2024, 12, &config).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(result.errors().is_empty() && result.warnings().is_empty(), "{:?} {:?}", result.errors(), result.warnings());
+    let utf8 = handle_month(&DirFetcher::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures")), 2024, 12, &config).await;
+    assert_eq!(without_dtstamp(&render_calendar(&[result], &config)), without_dtstamp(&render_calendar(&[utf8], &config)));
+}
+
+#[tokio::test]
+async fn fetch_failure_is_reported() {
+    let fetcher = HashMap::from([((2024, 12), String::from("<html></html>"))]);
+    let config = Config::default();
+
+    let result = handle_month(&fetcher, 2025, 1, &config).await;
+    assert_eq!(result.errors().len(), 1);
+    assert!(result.errors()[0].to_string().contains("2025-01"));
+    assert_eq!(render_calendar(&[result], &config).lines().filter(|l| *l == "BEGIN:VEVENT").count(), 0);
+}
+
+/// Serves the fixtures more slowly for the earlier months, so that the later ones come in first
+struct Slow(DirFetcher);
+
+impl CalendarFetcher for Slow {
+    async fn fetch(&self, year: usize, month: usize) -> anyhow::Result<String> {
+        let rank = (year * 12 + month) as u64;
+        tokio::time::sleep(std::time::Duration::from_millis((2025 * 12 + 2u64).saturating_sub(rank) * 50)).await;
+        self.0.fetch(year, month).await
+    }
+}
+
+#[tokio::test]
+async fn months_coming_out_of_order_are_put_back_in_order() {
+    let fetcher = Slow(DirFetcher::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures")));
+    let config = Config::default();
+    let months = ["2024-12", "2025-01", "2030-01"].map(|m| m.parse::<YearMonth>().unwrap());
+    let results = handle_months(&fetcher, &months, &config, None).await;
+    assert_eq!(results.iter().map(|r| (r.year(), r.month())).collect::<Vec<_>>(), [(2024, 12), (2025, 1), (2030, 1)]);
+    // The month without a fixture comes in first, and its error stays with it
+    assert!(results[0].errors().is_empty() && results[1].errors().is_empty());
+    assert!(results[2].errors()[0].to_string().contains("2030-01"));
+
+    let mut in_order = Vec::new();
+    for m in &months {
+        in_order.push(handle_month(&fetcher, m.year, m.month, &config).await);
+    }
+    assert_eq!(without_dtstamp(&render_calendar(&results, &config)), without_dtstamp(&render_calendar(&in_order, &config)));
+}
+
+#[tokio::test]
+async fn unchanged_months_are_not_reparsed() {
+    let page = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/2024-06.html")).unwrap();
+    let mut fetcher = HashMap::from([((2024, 6), page.clone())]);
+    let config = Config::default();
+
+    let first = handle_month_incremental(&fetcher, 2024, 6, &config, Some(&State::default())).await;
+    assert!(first.events_as_ics(&config).contains("SUMMARY:鳴尾浜 柔道場/自主稽古\n"));
+
+    // Tamper with the saved rendering: it only shows up in the output if the page was not parsed again
+    let state = State::from_results(&[first], &config);
+    let dir = std::env::temp_dir().join(format!("shinbukan-ics-state-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("state.json");
+    state.save(&path).unwrap();
+    let json = std::fs::read_to_string(&path).unwrap().replace("鳴尾浜", "Naruohama");
+    std::fs::write(&path, json).unwrap();
+    let state = State::load(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let second = handle_month_incremental(&fetcher, 2024, 6, &config, Some(&state)).await;
+    assert!(second.events_as_ics(&config).contains("SUMMARY:Naruohama 柔道場/自主稽古\n"));
+    assert!(second.served_stale());
+
+    // Once the page changes, the month gets parsed again
+    fetcher.insert((2024, 6), page.replace("自主稽古", "合同稽古"));
+    let third = handle_month_incremental(&fetcher, 2024, 6, &config, Some(&state)).await;
+    assert!(third.events_as_ics(&config).contains("SUMMARY:鳴尾浜 柔道場/合同稽古\n"));
+    assert!(!third.served_stale());
+}
+
+#[tokio::test]
+async fn dtstamps_of_unchanged_events_are_kept() {
+    let page = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/2024-06.html")).unwrap();
+    let fetcher = HashMap::from([((2024, 6), page.clone())]);
+    let config = Config { now: "2024-06-01T00:00:00Z".parse().unwrap(), ..Config::default() };
+    let first = handle_month_incremental(&fetcher, 2024, 6, &config, Some(&State::default())).await;
+    let state = State::from_results(&[first], &config);
+
+    // A day later, with one of the events changed so that the month is parsed again
+    let fetcher = HashMap::from([((2024, 6), page.replace("自主稽古", "合同稽古"))]);
+    let config = Config { now: "2024-06-02T00:00:00Z".parse().unwrap(), ..Config::default() };
+    let second = handle_month_incremental(&fetcher, 2024, 6, &config, Some(&state)).await;
+    let ics = second.events_as_ics(&config);
+    assert!(ics.contains("合同稽古"));
+    let stamps = ics.lines().filter_map(|l| l.strip_prefix("DTSTAMP:")).collect::<Vec<_>>();
+    assert_eq!(stamps.len(), 13);
+    assert_eq!(stamps.iter().filter(|s| **s == "20240602T000000Z").count(), ics.matches("合同稽古").count());
+    assert_eq!(stamps.iter().filter(|s| **s == "20240601T000000Z").count(), 13 - ics.matches("合同稽古").count());
+}
+
+#[tokio::test]
+async fn event_count_drop_is_an_error() {
+    let page = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/2024-06.html")).unwrap();
+    let fetcher = HashMap::from([((2024, 6), page.clone())]);
+    let config = Config { min_events: 13, ..Config::default() };
+    let results = [handle_month(&fetcher, 2024, 6, &config).await];
+    assert!(check_event_count(&results, &config, None).is_none());
+    let state = State::from_results(&results, &config);
+
+    // A page cut short after its first few days
+    let start = page.find("<td>\n4<br>").unwrap();
+    let end = start + page[start..].find("</table>").unwrap();
+    let broken = format!("{}{}", &page[..start], &page[end..]);
+    let fetcher = HashMap::from([((2024, 6), broken)]);
+    let results = [handle_month(&fetcher, 2024, 6, &config).await];
+    let err = check_event_count(&results, &config, None).unwrap();
+    assert!(err.to_string().contains("Found only 2 events in total, when at least 13 were expected"), "{err}");
+
+    let config = Config { min_events_vs_previous: Some(50), ..Config::default() };
+    assert!(check_event_count(&results, &config, None).is_none());
+    let err = check_event_count(&results, &config, Some(&state)).unwrap();
+    assert!(err.to_string().contains("at least 7 were expected"), "{err}");
+}